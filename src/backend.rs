@@ -0,0 +1,903 @@
+use bdk::{
+    bitcoin::{
+        consensus::deserialize, hashes::hex::FromHex, Block, BlockHash, OutPoint, Script,
+        Transaction, TxOut, Txid,
+    },
+    chain::{
+        keychain::{LocalChangeSet, LocalUpdate},
+        local_chain::LocalChain,
+        BlockId, ConfirmationTime, ConfirmationTimeAnchor,
+    },
+    KeychainKind, Wallet,
+};
+use bdk_electrum::{
+    electrum_client::{self, ElectrumApi},
+    ElectrumExt,
+};
+use bdk_file_store::Store;
+use bitcoincore_rpc::{Auth, Client, RpcApi};
+use cln_plugin::Error;
+use cln_rpc::ClnRpc;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Unused scripts past the last revealed index of each keychain queried by an
+/// incremental sync.
+pub const SYNC_LOOKAHEAD: u32 = 10;
+
+/// Number of `getrawtransaction` calls sent to bitcoind in one JSON-RPC batch when
+/// scanning the mempool.
+const MEMPOOL_BATCH: usize = 500;
+
+/// How much of a wallet's history to query when syncing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Discover history from scratch, scanning each keychain up to its gap limit.
+    Full,
+    /// Only query revealed scripts plus [`SYNC_LOOKAHEAD`], and unconfirmed txids.
+    /// The block-walking backends resume from their last checkpoint in both modes.
+    Incremental,
+}
+
+/// Kind of chain source a wallet is synced with.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendKind {
+    #[default]
+    Esplora,
+    Electrum,
+    Bitcoind,
+    /// lightningd's own bitcoin backend plugin, usually bcli.
+    Cln,
+}
+
+impl FromStr for BackendKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "esplora" => Ok(BackendKind::Esplora),
+            "electrum" => Ok(BackendKind::Electrum),
+            "bitcoind" => Ok(BackendKind::Bitcoind),
+            "cln" => Ok(BackendKind::Cln),
+            _ => Err(anyhow::anyhow!(
+                "unknown backend {s}. Expected one of: [esplora, electrum, bitcoind, cln]"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BackendKind::Esplora => write!(f, "esplora"),
+            BackendKind::Electrum => write!(f, "electrum"),
+            BackendKind::Bitcoind => write!(f, "bitcoind"),
+            BackendKind::Cln => write!(f, "cln"),
+        }
+    }
+}
+
+/// Plugin-wide chain source settings, from the `wd_*` options.
+#[derive(Debug, Clone, Default)]
+pub struct BackendConfig {
+    /// Backend used by wallets that don't pick their own.
+    pub kind: BackendKind,
+    pub esplora_url: Option<String>,
+    pub electrum_url: Option<String>,
+    pub bitcoind: Option<BitcoindConfig>,
+    /// lightningd's RPC socket, for the `cln` backend.
+    pub rpc_file: PathBuf,
+}
+
+impl BackendConfig {
+    /// Resolve the backend for a wallet, applying its per-wallet overrides on top of
+    /// the plugin-wide settings.
+    pub fn resolve(
+        &self,
+        kind: Option<BackendKind>,
+        esplora_url: Option<&String>,
+        electrum_url: Option<&String>,
+    ) -> Result<Backend, Error> {
+        match kind.unwrap_or(self.kind) {
+            BackendKind::Esplora => Ok(Backend::Esplora {
+                url: esplora_url.or(self.esplora_url.as_ref()).cloned(),
+            }),
+            BackendKind::Electrum => match electrum_url.or(self.electrum_url.as_ref()) {
+                Some(url) => Ok(Backend::Electrum { url: url.clone() }),
+                None => Err(anyhow::anyhow!(
+                    "electrum backend selected but no server url given. Set wd_electrum_url"
+                )),
+            },
+            BackendKind::Bitcoind => match &self.bitcoind {
+                Some(config) => Ok(Backend::Bitcoind(config.clone())),
+                None => Err(anyhow::anyhow!(
+                    "bitcoind backend selected but no rpc url given. Set wd_bitcoind_rpc_url"
+                )),
+            },
+            BackendKind::Cln => Ok(Backend::Cln {
+                rpc_file: self.rpc_file.clone(),
+            }),
+        }
+    }
+}
+
+/// Chain source used to sync a descriptor wallet.
+#[derive(Debug, Clone)]
+pub enum Backend {
+    /// Esplora HTTP API. Without a url, the default one for the wallet's network is used.
+    Esplora { url: Option<String> },
+    /// Electrum server, reached over `tcp://` or `ssl://`.
+    Electrum { url: String },
+    /// Bitcoin Core JSON-RPC, scanning blocks without a bitcoind wallet.
+    Bitcoind(BitcoindConfig),
+    /// lightningd's bitcoin backend, reached through its RPC socket.
+    Cln { rpc_file: PathBuf },
+}
+
+impl Backend {
+    pub fn kind(&self) -> BackendKind {
+        match self {
+            Backend::Esplora { .. } => BackendKind::Esplora,
+            Backend::Electrum { .. } => BackendKind::Electrum,
+            Backend::Bitcoind(_) => BackendKind::Bitcoind,
+            Backend::Cln { .. } => BackendKind::Cln,
+        }
+    }
+}
+
+/// Connection parameters for a bitcoind JSON-RPC server.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BitcoindConfig {
+    pub url: String,
+    pub auth: BitcoindAuth,
+    /// Whether to scan the mempool for unconfirmed transactions, which fetches
+    /// every mempool transaction on each sync.
+    #[serde(default)]
+    pub mempool: bool,
+}
+
+/// How to authenticate against bitcoind.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum BitcoindAuth {
+    None,
+    Cookie(PathBuf),
+    UserPass { user: String, password: String },
+}
+
+// the password must not reach lightningd's log
+impl fmt::Debug for BitcoindAuth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BitcoindAuth::None => write!(f, "None"),
+            BitcoindAuth::Cookie(path) => f.debug_tuple("Cookie").field(path).finish(),
+            BitcoindAuth::UserPass { user, .. } => f
+                .debug_struct("UserPass")
+                .field("user", user)
+                .field("password", &"<redacted>")
+                .finish(),
+        }
+    }
+}
+
+impl BitcoindConfig {
+    pub fn new(
+        url: String,
+        cookie: Option<String>,
+        user: Option<String>,
+        password: Option<String>,
+    ) -> Result<Self, Error> {
+        let auth = match (cookie, user, password) {
+            (Some(cookie), None, None) => BitcoindAuth::Cookie(PathBuf::from(cookie)),
+            (None, Some(user), Some(password)) => BitcoindAuth::UserPass { user, password },
+            (None, None, None) => BitcoindAuth::None,
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "bitcoind auth needs either a cookie file or both a user and a password"
+                ))
+            }
+        };
+        Ok(Self {
+            url,
+            auth,
+            mempool: false,
+        })
+    }
+
+    fn client(&self) -> Result<Client, Error> {
+        let auth = match &self.auth {
+            BitcoindAuth::None => Auth::None,
+            BitcoindAuth::Cookie(path) => Auth::CookieFile(path.clone()),
+            BitcoindAuth::UserPass { user, password } => {
+                Auth::UserPass(user.clone(), password.clone())
+            }
+        };
+        Ok(Client::new(&self.url, auth)?)
+    }
+}
+
+/// Sync `wallet` by walking blocks from bitcoind, starting after the last
+/// checkpoint still in the node's best chain, or at `birthday` on first sync.
+/// The mempool is scanned last, if `config` asks for it.
+pub fn sync_bitcoind<'a>(
+    wallet: &mut Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+    config: &BitcoindConfig,
+    birthday: Option<u32>,
+    stop_gap: usize,
+) -> Result<(), Error> {
+    let client = config.client()?;
+    let tip_height = client.get_block_count()? as u32;
+
+    let mut scan = BlockScan::new(wallet, stop_gap);
+    let checkpoints = wallet.checkpoints().clone();
+    for (&height, &hash) in checkpoints.iter().rev() {
+        if height > tip_height {
+            continue;
+        }
+        if scan.check_point(height, hash, client.get_block_hash(height as u64)?) {
+            break;
+        }
+    }
+    let start_height = scan.start_height(wallet, birthday);
+    log::info!(
+        "scanning blocks {} to {} from bitcoind",
+        start_height,
+        tip_height
+    );
+
+    for height in start_height..=tip_height {
+        let hash = client.get_block_hash(height as u64)?;
+        let block = client.get_block(&hash)?;
+        scan.scan_block(wallet, height, block, height == tip_height);
+    }
+
+    if config.mempool {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let txids = client.get_raw_mempool()?;
+        log::info!(
+            "scanning {} mempool transactions from bitcoind",
+            txids.len()
+        );
+        for tx in mempool_txs(&client, &txids)? {
+            scan.scan_unconfirmed(wallet, tx, now);
+        }
+    }
+
+    scan.apply(wallet)
+}
+
+/// Fetch the mempool transactions `txids`, [`MEMPOOL_BATCH`] per request. Those
+/// evicted or mined since `getrawmempool` are left out.
+fn mempool_txs(client: &Client, txids: &[Txid]) -> Result<Vec<Transaction>, Error> {
+    let rpc = client.get_jsonrpc_client();
+    let mut txs = Vec::new();
+    for chunk in txids.chunks(MEMPOOL_BATCH) {
+        let params = chunk
+            .iter()
+            .map(|txid| Ok(vec![serde_json::value::to_raw_value(txid)?]))
+            .collect::<Result<Vec<_>, serde_json::Error>>()?;
+        let requests = params
+            .iter()
+            .map(|params| rpc.build_request("getrawtransaction", params))
+            .collect::<Vec<_>>();
+        for response in rpc.send_batch(&requests)?.into_iter().flatten() {
+            if let Ok(hex) = response.result::<String>() {
+                txs.push(deserialize(&Vec::<u8>::from_hex(&hex)?)?);
+            }
+        }
+    }
+    Ok(txs)
+}
+
+#[derive(Deserialize)]
+struct ChainInfo {
+    blockcount: u32,
+}
+
+#[derive(Deserialize)]
+struct RawBlock {
+    blockhash: Option<String>,
+    block: Option<String>,
+}
+
+async fn get_raw_block(rpc: &mut ClnRpc, height: u32) -> Result<(BlockHash, Block), Error> {
+    let raw: RawBlock = rpc
+        .call_raw("getrawblockbyheight", &json!({ "height": height }))
+        .await
+        .map_err(|e| anyhow::anyhow!("Error calling getrawblockbyheight: {:?}", e))?;
+    match (raw.blockhash, raw.block) {
+        (Some(hash), Some(block)) => Ok((
+            BlockHash::from_str(&hash)?,
+            deserialize(&Vec::<u8>::from_hex(&block)?)?,
+        )),
+        _ => Err(anyhow::anyhow!("block {} not found by lightningd", height)),
+    }
+}
+
+/// Sync `wallet` by walking blocks through lightningd's bitcoin backend plugin
+/// (`getchaininfo` and `getrawblockbyheight`), starting after the last checkpoint
+/// still in the node's best chain, or at `birthday` on first sync.
+///
+/// The bitcoin backend doesn't expose the mempool, so only confirmed
+/// transactions are found.
+pub async fn sync_cln<'a>(
+    wallet: &mut Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+    rpc_file: &Path,
+    birthday: Option<u32>,
+    stop_gap: usize,
+) -> Result<(), Error> {
+    let mut rpc = ClnRpc::new(rpc_file).await?;
+    let chain_info: ChainInfo = rpc
+        .call_raw("getchaininfo", &json!({}))
+        .await
+        .map_err(|e| anyhow::anyhow!("Error calling getchaininfo: {:?}", e))?;
+    let tip_height = chain_info.blockcount;
+
+    let mut scan = BlockScan::new(wallet, stop_gap);
+    let checkpoints = wallet.checkpoints().clone();
+    for (&height, &hash) in checkpoints.iter().rev() {
+        if height > tip_height {
+            continue;
+        }
+        let (node_hash, _) = get_raw_block(&mut rpc, height).await?;
+        if scan.check_point(height, hash, node_hash) {
+            break;
+        }
+    }
+    let start_height = scan.start_height(wallet, birthday);
+    log::info!(
+        "scanning blocks {} to {} from lightningd",
+        start_height,
+        tip_height
+    );
+
+    for height in start_height..=tip_height {
+        let (_, block) = get_raw_block(&mut rpc, height).await?;
+        scan.scan_block(wallet, height, block, height == tip_height);
+    }
+
+    scan.apply(wallet)
+}
+
+/// Scripts revealed so far in each keychain, plus `lookahead` unused ones.
+pub fn incremental_spks<'a>(
+    wallet: &Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+    lookahead: u32,
+) -> BTreeMap<KeychainKind, Vec<(u32, Script)>> {
+    wallet
+        .spks_of_all_keychains()
+        .into_iter()
+        .map(|(k, k_spks)| {
+            let count = wallet
+                .spk_index()
+                .last_revealed_index(&k)
+                .map_or(0, |i| i + 1)
+                + lookahead;
+            (k, k_spks.take(count as usize).collect())
+        })
+        .collect()
+}
+
+/// Txids of the wallet's transactions that are not confirmed yet.
+pub fn unconfirmed_txids<'a>(
+    wallet: &Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+) -> Vec<Txid> {
+    wallet
+        .transactions()
+        .filter_map(|tx| wallet.get_tx(tx.node.txid, false))
+        .filter(|tx| matches!(tx.confirmation_time, ConfirmationTime::Unconfirmed { .. }))
+        .map(|tx| tx.txid)
+        .collect()
+}
+
+/// Update built by walking blocks one by one, for backends that have no
+/// script pubkey index.
+///
+/// Transactions are matched against the wallet's script pubkeys, looking `stop_gap`
+/// scripts past the last used index of each keychain, and against outputs already
+/// in the wallet's tx graph.
+struct BlockScan {
+    spks: SpkLookup,
+    update: LocalUpdate<KeychainKind, ConfirmationTimeAnchor>,
+    blocks: BTreeMap<u32, BlockHash>,
+    agreement: Option<u32>,
+}
+
+impl BlockScan {
+    fn new<'a>(
+        wallet: &Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+        stop_gap: usize,
+    ) -> Self {
+        Self {
+            spks: SpkLookup::new(wallet, stop_gap),
+            update: LocalUpdate::new(LocalChain::default()),
+            blocks: BTreeMap::new(),
+            agreement: None,
+        }
+    }
+
+    /// Compare a local checkpoint with the node's hash at that height. Call from the
+    /// highest checkpoint down until it returns true, i.e. the checkpoint is still in
+    /// the best chain.
+    fn check_point(&mut self, height: u32, hash: BlockHash, node_hash: BlockHash) -> bool {
+        // the update must replace every stale checkpoint
+        self.blocks.insert(height, node_hash);
+        if node_hash == hash {
+            self.agreement = Some(height);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn start_height<'a>(
+        &self,
+        wallet: &Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+        birthday: Option<u32>,
+    ) -> u32 {
+        match self.agreement {
+            Some(height) => height + 1,
+            None => {
+                if !wallet.checkpoints().is_empty() {
+                    log::warn!("no local checkpoint found in the node's best chain, rescanning");
+                }
+                birthday.unwrap_or(0)
+            }
+        }
+    }
+
+    fn scan_block<'a>(
+        &mut self,
+        wallet: &Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+        height: u32,
+        block: Block,
+        is_tip: bool,
+    ) {
+        let hash = block.block_hash();
+        let mut found = false;
+        for tx in block.txdata {
+            if self.spks.is_relevant(wallet, &tx) {
+                let txid = tx.txid();
+                let _ = self.update.graph.insert_tx(tx);
+                let _ = self.update.graph.insert_anchor(
+                    txid,
+                    ConfirmationTimeAnchor {
+                        anchor_block: BlockId { height, hash },
+                        confirmation_height: height,
+                        confirmation_time: block.header.time as u64,
+                    },
+                );
+                found = true;
+            }
+        }
+        if found || is_tip {
+            self.blocks.insert(height, hash);
+        }
+    }
+
+    fn scan_unconfirmed<'a>(
+        &mut self,
+        wallet: &Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+        tx: Transaction,
+        seen_at: u64,
+    ) {
+        if self.spks.is_relevant(wallet, &tx) {
+            let txid = tx.txid();
+            // keep the time it was first seen, so that a transaction still in the
+            // mempool doesn't look seen anew on every sync
+            if wallet.tx_graph().get_tx(txid).is_none() {
+                let _ = self.update.graph.insert_seen_at(txid, seen_at);
+            }
+            let _ = self.update.graph.insert_tx(tx);
+        }
+    }
+
+    fn apply<'a>(
+        mut self,
+        wallet: &mut Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+    ) -> Result<(), Error> {
+        self.update.chain = LocalChain::from(self.blocks);
+        self.update.last_active_indices = self.spks.last_active;
+        wallet.apply_update(self.update)?;
+        Ok(())
+    }
+}
+
+/// Sync `wallet` from an Electrum server. A url without a scheme is reached over
+/// plain TCP.
+///
+/// Like Esplora, a full scan queries keychains until `stop_gap` consecutive unused
+/// scripts are found, fetching `batch_size` scripts per request.
+pub fn sync_electrum<'a>(
+    wallet: &mut Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+    url: &str,
+    mode: SyncMode,
+    stop_gap: usize,
+    batch_size: usize,
+) -> Result<(), Error> {
+    let client = electrum_client(url)?;
+
+    let electrum_update = match mode {
+        SyncMode::Full => {
+            let keychain_spks = wallet
+                .spks_of_all_keychains()
+                .into_iter()
+                .map(|(k, k_spks)| {
+                    log::info!("Scanning keychain [{:?}]", k);
+                    (k, k_spks)
+                })
+                .collect::<BTreeMap<_, _>>();
+            client.scan(
+                wallet.checkpoints(),
+                keychain_spks,
+                None,
+                None,
+                stop_gap,
+                batch_size,
+            )?
+        }
+        SyncMode::Incremental => {
+            let keychain_spks = incremental_spks(wallet, SYNC_LOOKAHEAD);
+            let spk_count = keychain_spks.values().map(Vec::len).sum::<usize>();
+            client.scan(
+                wallet.checkpoints(),
+                keychain_spks,
+                unconfirmed_txids(wallet),
+                None,
+                // the scripts are already bounded, query all of them
+                spk_count,
+                batch_size,
+            )?
+        }
+    };
+    let missing_txs =
+        client.batch_transaction_get(electrum_update.missing_full_txs(wallet.as_ref()))?;
+    let update = electrum_update.finalize_as_confirmation_time(&client, None, missing_txs)?;
+    wallet.apply_update(update)?;
+    Ok(())
+}
+
+/// Client for an Electrum server. A url without a scheme is reached over plain TCP.
+fn electrum_client(url: &str) -> Result<electrum_client::Client, Error> {
+    let url = if url.starts_with("tcp://") || url.starts_with("ssl://") {
+        url.to_owned()
+    } else {
+        format!("tcp://{url}")
+    };
+    Ok(electrum_client::Client::new(&url)?)
+}
+
+/// The outputs at `outpoints` of `tx`, if it is their transaction.
+pub fn outputs_of(tx: &Transaction, outpoints: &[OutPoint]) -> Vec<(OutPoint, TxOut)> {
+    let txid = tx.txid();
+    outpoints
+        .iter()
+        .filter(|outpoint| outpoint.txid == txid)
+        .filter_map(|outpoint| {
+            let txout = tx.output.get(outpoint.vout as usize)?;
+            Some((*outpoint, txout.clone()))
+        })
+        .collect()
+}
+
+/// Look up the outputs at `outpoints` with `getrawtransaction`. Confirmed
+/// transactions that aren't in the mempool are only found with `-txindex`, and
+/// the ones bitcoind can't find are left out.
+pub fn bitcoind_outputs(
+    config: &BitcoindConfig,
+    outpoints: &[OutPoint],
+) -> Result<Vec<(OutPoint, TxOut)>, Error> {
+    let client = config.client()?;
+    let txids = outpoints.iter().map(|o| o.txid).collect::<HashSet<_>>();
+    let mut outputs = Vec::new();
+    for txid in txids {
+        match client.get_raw_transaction(&txid, None) {
+            Ok(tx) => outputs.extend(outputs_of(&tx, outpoints)),
+            Err(e) => log::info!("bitcoind can't find tx {}: {}", txid, e),
+        }
+    }
+    Ok(outputs)
+}
+
+/// Look up the outputs at `outpoints` on an Electrum server.
+pub fn electrum_outputs(
+    url: &str,
+    outpoints: &[OutPoint],
+) -> Result<Vec<(OutPoint, TxOut)>, Error> {
+    let client = electrum_client(url)?;
+    let txids = outpoints.iter().map(|o| o.txid).collect::<HashSet<_>>();
+    let txs = client.batch_transaction_get(txids.iter())?;
+    Ok(txs
+        .iter()
+        .flat_map(|tx| outputs_of(tx, outpoints))
+        .collect())
+}
+
+/// Script pubkeys of each keychain, derived lazily up to `stop_gap` past the
+/// last index seen in a transaction, along with the outpoints found paying to them.
+struct SpkLookup {
+    spks: HashMap<Script, (KeychainKind, u32)>,
+    outpoints: HashSet<OutPoint>,
+    derived: BTreeMap<KeychainKind, u32>,
+    last_active: BTreeMap<KeychainKind, u32>,
+    stop_gap: u32,
+}
+
+impl SpkLookup {
+    fn new<'a>(
+        wallet: &Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+        stop_gap: usize,
+    ) -> Self {
+        let mut lookup = Self {
+            spks: HashMap::new(),
+            outpoints: HashSet::new(),
+            derived: BTreeMap::new(),
+            last_active: BTreeMap::new(),
+            stop_gap: stop_gap as u32,
+        };
+        for keychain in wallet.spks_of_all_keychains().into_keys() {
+            let last_revealed = wallet.spk_index().last_revealed_index(&keychain);
+            if let Some(index) = last_revealed {
+                lookup.last_active.insert(keychain, index);
+            }
+            lookup.derive_to(
+                wallet,
+                keychain,
+                last_revealed.unwrap_or(0) + lookup.stop_gap,
+            );
+        }
+        lookup
+    }
+
+    fn derive_to<'a>(
+        &mut self,
+        wallet: &Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+        keychain: KeychainKind,
+        target: u32,
+    ) {
+        let from = self.derived.get(&keychain).map_or(0, |i| i + 1);
+        if from > target {
+            return;
+        }
+        let descriptor = wallet.get_descriptor_for_keychain(keychain);
+        for index in from..=target {
+            let spk = descriptor.at_derivation_index(index).script_pubkey();
+            self.spks.insert(spk, (keychain, index));
+        }
+        self.derived.insert(keychain, target);
+    }
+
+    /// Whether `tx` pays to one of our scripts or spends one of our outputs.
+    fn is_relevant<'a>(
+        &mut self,
+        wallet: &Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+        tx: &Transaction,
+    ) -> bool {
+        let mut relevant = tx.input.iter().any(|input| {
+            self.outpoints.contains(&input.previous_output)
+                || match wallet.tx_graph().get_txout(input.previous_output) {
+                    Some(txout) => wallet.is_mine(&txout.script_pubkey),
+                    None => false,
+                }
+        });
+        let txid = tx.txid();
+        for (vout, output) in tx.output.iter().enumerate() {
+            if let Some(&(keychain, index)) = self.spks.get(&output.script_pubkey) {
+                relevant = true;
+                self.outpoints.insert(OutPoint::new(txid, vout as u32));
+                let last_active = self.last_active.entry(keychain).or_insert(index);
+                *last_active = (*last_active).max(index);
+                self.derive_to(wallet, keychain, index + self.stop_gap);
+            }
+        }
+        relevant
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bdk::bitcoin::{hashes::Hash, Network, PackedLockTime, Sequence, TxIn, TxOut, Witness};
+
+    const DESCRIPTOR: &str = "wpkh(tpubD6NzVbkrYhZ4WuCUnMVvxVDcynH1LCFPd8xwMnAckE5Jg7qG8H1JAPUM1XM6PUrDJkkavEYjAysfrcYkTP4uN5maxw33fYynKXFUpKHpBbM/0/*)";
+    const CHANGE_DESCRIPTOR: &str = "wpkh(tpubD6NzVbkrYhZ4WuCUnMVvxVDcynH1LCFPd8xwMnAckE5Jg7qG8H1JAPUM1XM6PUrDJkkavEYjAysfrcYkTP4uN5maxw33fYynKXFUpKHpBbM/1/*)";
+
+    fn test_wallet<'a>(
+        name: &str,
+    ) -> Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>> {
+        let path =
+            std::env::temp_dir().join(format!("smaug-backend-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let db = Store::new_from_path("smaug-test".as_bytes(), path).expect("store");
+        Wallet::new(DESCRIPTOR, Some(CHANGE_DESCRIPTOR), db, Network::Regtest).expect("wallet")
+    }
+
+    fn spk_at(
+        wallet: &Wallet<Store<LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+        index: u32,
+    ) -> Script {
+        wallet
+            .get_descriptor_for_keychain(KeychainKind::External)
+            .at_derivation_index(index)
+            .script_pubkey()
+    }
+
+    fn tx(inputs: Vec<OutPoint>, outputs: Vec<Script>) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: inputs
+                .into_iter()
+                .map(|previous_output| TxIn {
+                    previous_output,
+                    script_sig: Script::new(),
+                    sequence: Sequence::MAX,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: outputs
+                .into_iter()
+                .map(|script_pubkey| TxOut {
+                    value: 10_000,
+                    script_pubkey,
+                })
+                .collect(),
+        }
+    }
+
+    fn block_hash(n: u8) -> BlockHash {
+        BlockHash::from_inner([n; 32])
+    }
+
+    fn config() -> BackendConfig {
+        BackendConfig {
+            kind: BackendKind::Esplora,
+            esplora_url: Some("https://esplora.example.com".to_owned()),
+            electrum_url: None,
+            bitcoind: None,
+            rpc_file: PathBuf::from("/tmp/lightning-rpc"),
+        }
+    }
+
+    #[test]
+    fn bitcoind_auth() {
+        let url = "http://127.0.0.1:18443".to_owned();
+        let s = |s: &str| Some(s.to_owned());
+        assert!(matches!(
+            BitcoindConfig::new(url.clone(), s("/tmp/.cookie"), None, None)
+                .unwrap()
+                .auth,
+            BitcoindAuth::Cookie(path) if path == PathBuf::from("/tmp/.cookie")
+        ));
+        assert!(matches!(
+            BitcoindConfig::new(url.clone(), None, s("user"), s("pass"))
+                .unwrap()
+                .auth,
+            BitcoindAuth::UserPass { user, password } if user == "user" && password == "pass"
+        ));
+        assert!(matches!(
+            BitcoindConfig::new(url.clone(), None, None, None)
+                .unwrap()
+                .auth,
+            BitcoindAuth::None
+        ));
+        assert!(BitcoindConfig::new(url.clone(), None, s("user"), None).is_err());
+        assert!(BitcoindConfig::new(url.clone(), None, None, s("pass")).is_err());
+        assert!(BitcoindConfig::new(url, s("/tmp/.cookie"), s("user"), s("pass")).is_err());
+    }
+
+    #[test]
+    fn bitcoind_password_is_redacted() {
+        let config = BitcoindConfig::new(
+            "http://127.0.0.1:18443".to_owned(),
+            None,
+            Some("user".to_owned()),
+            Some("hunter2".to_owned()),
+        )
+        .unwrap();
+        let debug = format!("{:?}", config);
+        assert!(debug.contains("user"));
+        assert!(!debug.contains("hunter2"));
+    }
+
+    #[test]
+    fn resolve_defaults_and_overrides() {
+        let mut config = config();
+        assert!(matches!(
+            config.resolve(None, None, None).unwrap(),
+            Backend::Esplora { url: Some(url) } if url == "https://esplora.example.com"
+        ));
+        let own_url = "https://own.example.com".to_owned();
+        assert!(matches!(
+            config.resolve(None, Some(&own_url), None).unwrap(),
+            Backend::Esplora { url: Some(url) } if url == own_url
+        ));
+
+        // a wallet's backend overrides the plugin-wide one, and needs its settings
+        assert!(config
+            .resolve(Some(BackendKind::Electrum), None, None)
+            .is_err());
+        let electrum_url = "ssl://electrum.example.com:50002".to_owned();
+        assert!(matches!(
+            config.resolve(Some(BackendKind::Electrum), None, Some(&electrum_url)).unwrap(),
+            Backend::Electrum { url } if url == electrum_url
+        ));
+        config.electrum_url = Some("tcp://127.0.0.1:50001".to_owned());
+        assert!(matches!(
+            config.resolve(Some(BackendKind::Electrum), None, None).unwrap(),
+            Backend::Electrum { url } if url == "tcp://127.0.0.1:50001"
+        ));
+
+        assert!(config
+            .resolve(Some(BackendKind::Bitcoind), None, None)
+            .is_err());
+        config.bitcoind = Some(
+            BitcoindConfig::new("http://127.0.0.1:18443".to_owned(), None, None, None).unwrap(),
+        );
+        assert!(matches!(
+            config.resolve(Some(BackendKind::Bitcoind), None, None).unwrap(),
+            Backend::Bitcoind(BitcoindConfig { url, .. }) if url == "http://127.0.0.1:18443"
+        ));
+
+        assert!(matches!(
+            config.resolve(Some(BackendKind::Cln), None, None).unwrap(),
+            Backend::Cln { rpc_file } if rpc_file == PathBuf::from("/tmp/lightning-rpc")
+        ));
+    }
+
+    #[test]
+    fn check_point_records_node_hashes() {
+        let wallet = test_wallet("check-point");
+        let mut scan = BlockScan::new(&wallet, 5);
+        // stale checkpoint: its height is rewritten with the node's hash
+        assert!(!scan.check_point(110, block_hash(1), block_hash(2)));
+        assert_eq!(scan.blocks.get(&110), Some(&block_hash(2)));
+        assert_eq!(scan.agreement, None);
+        assert!(scan.check_point(100, block_hash(3), block_hash(3)));
+        assert_eq!(scan.agreement, Some(100));
+    }
+
+    #[test]
+    fn start_height() {
+        let wallet = test_wallet("start-height");
+        let mut scan = BlockScan::new(&wallet, 5);
+        assert_eq!(scan.start_height(&wallet, None), 0);
+        assert_eq!(scan.start_height(&wallet, Some(800_000)), 800_000);
+        scan.check_point(100, block_hash(3), block_hash(3));
+        assert_eq!(scan.start_height(&wallet, Some(800_000)), 101);
+    }
+
+    #[test]
+    fn is_relevant() {
+        let wallet = test_wallet("is-relevant");
+        let mut spks = SpkLookup::new(&wallet, 5);
+
+        assert!(!spks.is_relevant(&wallet, &tx(vec![], vec![Script::new()])));
+        // past the gap limit until a closer script is used
+        let far = tx(vec![], vec![spk_at(&wallet, 8)]);
+        assert!(!spks.is_relevant(&wallet, &far));
+
+        let near = tx(vec![], vec![spk_at(&wallet, 3)]);
+        assert!(spks.is_relevant(&wallet, &near));
+        assert_eq!(spks.last_active.get(&KeychainKind::External), Some(&3));
+        assert!(spks.is_relevant(&wallet, &far));
+        assert_eq!(spks.last_active.get(&KeychainKind::External), Some(&8));
+
+        // spending an output found earlier in the scan
+        let spend = tx(vec![OutPoint::new(near.txid(), 0)], vec![Script::new()]);
+        assert!(spks.is_relevant(&wallet, &spend));
+        let other = tx(
+            vec![OutPoint::new(Txid::from_inner([7; 32]), 0)],
+            vec![Script::new()],
+        );
+        assert!(!spks.is_relevant(&wallet, &other));
+    }
+}
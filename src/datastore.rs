@@ -0,0 +1,544 @@
+use cln_plugin::Error;
+use cln_rpc::{
+    model::{
+        requests::{DatastoreRequest, DeldatastoreRequest, ListdatastoreRequest},
+        responses::ListdatastoreDatastore,
+        DatastoreMode,
+    },
+    ClnRpc, Request, Response, RpcError,
+};
+use serde_json::{Map, Value};
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::SmaugError,
+    wallet::{DescriptorWallet, STOP_GAP},
+};
+
+/// Root of smaug's keys in lightningd's datastore.
+pub const DATASTORE_ROOT: &str = "smaug";
+/// Where the whole legacy single-key layout is kept while it's being migrated.
+const LEGACY_BACKUP_ROOT: &str = "smaug-legacy";
+/// Where records smaug can't read are moved, under their original key, so that
+/// they can be inspected and repaired.
+pub const QUARANTINE_ROOT: &str = "smaug-quarantine";
+/// lightningd's error codes for a write or delete that doesn't match the key as
+/// smaug last saw it: deleting with an outdated generation (1201), creating a key
+/// that now exists (1202), replacing one that no longer does (1203), and replacing
+/// with an outdated generation (1204).
+const CONFLICT_CODES: [i32; 4] = [1201, 1202, 1203, 1204];
+
+/// Version of the wallet records smaug writes, stored in their `schema_version`
+/// field. Records without one are version 0.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// A step upgrading a wallet record, with its transactions under `transactions`,
+/// from a schema version to the next.
+type Migration = fn(&mut Map<String, Value>) -> Result<(), String>;
+
+/// `MIGRATIONS[n]` upgrades records from version `n` to `n + 1`. A change to the
+/// persisted fields of [`DescriptorWallet`] that serde defaults don't cover bumps
+/// [`SCHEMA_VERSION`] and adds a step here.
+const MIGRATIONS: [Migration; SCHEMA_VERSION as usize] = [to_v1, to_v2];
+
+/// Wallets added before smaug had defaults were stored without a birthday or gap
+/// limit.
+fn to_v1(record: &mut Map<String, Value>) -> Result<(), String> {
+    for (field, default) in [("birthday", 0), ("gap", STOP_GAP as u64)] {
+        let value = record.entry(field).or_insert(Value::Null);
+        if value.is_null() {
+            *value = default.into();
+        }
+    }
+    Ok(())
+}
+
+/// Earlier versions recorded unconfirmed transactions as notified, although their
+/// events are only sent once they confirm. Forgetting them lets them be announced
+/// as pending, and notified when they confirm.
+fn to_v2(record: &mut Map<String, Value>) -> Result<(), String> {
+    if let Some(Value::Object(transactions)) = record.get_mut("transactions") {
+        transactions.retain(|_, tx| match tx.get("confirmation_time") {
+            Some(Value::Object(time)) => !time.contains_key("Unconfirmed"),
+            Some(Value::Null) => false,
+            _ => true,
+        });
+    }
+    Ok(())
+}
+
+/// Key of a wallet's settings and tracking state, without its transactions.
+pub fn wallet_key(name: &str) -> Vec<String> {
+    vec![
+        DATASTORE_ROOT.to_owned(),
+        "wallets".to_owned(),
+        name.to_owned(),
+    ]
+}
+
+/// Key of the transactions a wallet was notified about.
+pub fn transactions_key(name: &str) -> Vec<String> {
+    vec![
+        DATASTORE_ROOT.to_owned(),
+        "transactions".to_owned(),
+        name.to_owned(),
+    ]
+}
+
+/// Watched wallets in lightningd's datastore, each under its own keys. Every
+/// write is a compare-and-swap on the generation smaug last saw for the key, so
+/// that concurrent writers fail instead of overwriting each other.
+#[derive(Debug, Clone, Default)]
+pub struct Datastore {
+    rpc_file: PathBuf,
+    generations: BTreeMap<Vec<String>, u64>,
+    /// Hash of the value smaug last wrote to each key, to skip unchanged writes.
+    written: BTreeMap<Vec<String>, u64>,
+}
+
+/// A stored wallet record, as read by [`parse_wallet`].
+enum Parsed {
+    /// A wallet, upgraded from `version` if it's older than [`SCHEMA_VERSION`].
+    Wallet { dw: DescriptorWallet, version: u32 },
+    /// A record written by a newer smaug, which is left alone.
+    Newer(u32),
+}
+
+impl Datastore {
+    pub fn new(rpc_file: impl Into<PathBuf>) -> Self {
+        Datastore {
+            rpc_file: rpc_file.into(),
+            generations: BTreeMap::new(),
+            written: BTreeMap::new(),
+        }
+    }
+
+    /// Read every stored wallet, first migrating the legacy layout where all of
+    /// them were a single JSON object under [`DATASTORE_ROOT`]. Records of older
+    /// schema versions are upgraded and written back. Unreadable ones are moved to
+    /// [`QUARANTINE_ROOT`] so that the remaining wallets still load.
+    pub async fn load(&mut self) -> Result<BTreeMap<String, DescriptorWallet>, Error> {
+        self.migrate_legacy().await?;
+
+        let mut wallets = BTreeMap::new();
+        let records = self
+            .list(&[DATASTORE_ROOT.to_owned(), "wallets".to_owned()])
+            .await?;
+        for record in records {
+            let name = match record.key.as_slice() {
+                [_, _, name] => name.clone(),
+                _ => continue,
+            };
+            let string = match record.string {
+                Some(string) => string,
+                None => continue,
+            };
+            if let Some(generation) = record.generation {
+                self.generations.insert(record.key.clone(), generation);
+            }
+            let key = transactions_key(&name);
+            let mut transactions = None;
+            if let Some(record) = self.list(&key).await?.into_iter().find(|r| r.key == key) {
+                if let Some(generation) = record.generation {
+                    self.generations.insert(key, generation);
+                }
+                transactions = record.string;
+            }
+            match parse_wallet(&string, transactions.as_deref()) {
+                Ok(Parsed::Wallet { dw, version }) => {
+                    if version < SCHEMA_VERSION {
+                        log::info!(
+                            "upgrading wallet {} from schema version {} to {}",
+                            name,
+                            version,
+                            SCHEMA_VERSION
+                        );
+                        self.save_wallet(&name, &dw).await?;
+                    }
+                    wallets.insert(name, dw);
+                }
+                Ok(Parsed::Newer(version)) => log::error!(
+                    "wallet {} has schema version {}, but this smaug only reads up to {}. \
+                    Skipping it until smaug is upgraded",
+                    name,
+                    version,
+                    SCHEMA_VERSION
+                ),
+                Err(reason) => {
+                    log::error!(
+                        "can't read wallet {}: {}. Moving its records to {}",
+                        name,
+                        reason,
+                        QUARANTINE_ROOT
+                    );
+                    self.quarantine(&wallet_key(&name), string).await?;
+                    if let Some(transactions) = transactions {
+                        self.quarantine(&transactions_key(&name), transactions)
+                            .await?;
+                    }
+                }
+            }
+        }
+        Ok(wallets)
+    }
+
+    /// Move the legacy single-key layout to per-wallet keys. The old object is
+    /// first copied to [`LEGACY_BACKUP_ROOT`], since lightningd doesn't allow keys
+    /// under one that has a value, so that a migration cut short resumes from it.
+    /// Wallets are copied as they are, and upgraded by [`Datastore::load`].
+    async fn migrate_legacy(&mut self) -> Result<(), Error> {
+        let root = vec![DATASTORE_ROOT.to_owned()];
+        let backup = vec![LEGACY_BACKUP_ROOT.to_owned()];
+        let legacy = match self.get(&root).await? {
+            Some(legacy) => {
+                log::info!("migrating wallets from the legacy datastore layout");
+                self.write(
+                    &backup,
+                    legacy.clone(),
+                    DatastoreMode::CREATE_OR_REPLACE,
+                    None,
+                )
+                .await?;
+                self.delete(&root).await?;
+                legacy
+            }
+            None => match self.get(&backup).await? {
+                Some(legacy) => {
+                    log::info!("resuming an interrupted datastore migration");
+                    legacy
+                }
+                None => return Ok(()),
+            },
+        };
+        let wallets: BTreeMap<String, Value> = match serde_json::from_str(&legacy) {
+            Ok(wallets) => wallets,
+            Err(e) => {
+                log::error!(
+                    "can't read the legacy datastore layout: {}. Moving it to {}",
+                    e,
+                    QUARANTINE_ROOT
+                );
+                self.quarantine(&backup, legacy).await?;
+                return Ok(());
+            }
+        };
+        for (name, wallet) in &wallets {
+            for (key, value) in records(name, wallet.clone()) {
+                self.write(
+                    &key,
+                    value.to_string(),
+                    DatastoreMode::CREATE_OR_REPLACE,
+                    None,
+                )
+                .await?;
+            }
+        }
+        self.delete(&backup).await?;
+        log::info!("migrated {} wallets", wallets.len());
+        Ok(())
+    }
+
+    /// Write a wallet's records, replacing the ones smaug read or wrote last.
+    /// Transactions are written first, so that a save cut short loses the rest of
+    /// the wallet's state rather than which transactions were notified, and they
+    /// aren't notified again. Records that didn't change since they were last
+    /// written are skipped.
+    pub async fn save_wallet(&mut self, name: &str, dw: &DescriptorWallet) -> Result<(), Error> {
+        let mut wallet = serde_json::to_value(dw)?;
+        if let Value::Object(m) = &mut wallet {
+            m.insert("schema_version".to_owned(), SCHEMA_VERSION.into());
+        }
+        for (key, value) in records(name, wallet) {
+            let value = value.to_string();
+            if self.written.get(&key) == Some(&hash(&value)) {
+                continue;
+            }
+            let (mode, generation) = match self.generations.get(&key) {
+                Some(&generation) => (DatastoreMode::MUST_REPLACE, Some(generation)),
+                None => (DatastoreMode::MUST_CREATE, None),
+            };
+            self.write(&key, value, mode, generation).await?;
+        }
+        Ok(())
+    }
+
+    /// Delete a wallet's records.
+    pub async fn delete_wallet(&mut self, name: &str) -> Result<(), Error> {
+        for key in [wallet_key(name), transactions_key(name)] {
+            if self.generations.contains_key(&key) {
+                self.delete(&key).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Move the record at `key`, whose value is `string`, to [`QUARANTINE_ROOT`].
+    async fn quarantine(&mut self, key: &[String], string: String) -> Result<(), Error> {
+        let mut target = vec![QUARANTINE_ROOT.to_owned()];
+        target.extend_from_slice(key);
+        self.write(&target, string, DatastoreMode::CREATE_OR_REPLACE, None)
+            .await?;
+        self.generations.remove(&target);
+        self.written.remove(&target);
+        self.delete(key).await
+    }
+
+    async fn rpc(&self) -> Result<ClnRpc, SmaugError> {
+        ClnRpc::new(Path::new(&self.rpc_file))
+            .await
+            .map_err(|e| SmaugError::Datastore(format!("{:?}", e)))
+    }
+
+    async fn list(&self, key: &[String]) -> Result<Vec<ListdatastoreDatastore>, Error> {
+        let response = self
+            .rpc()
+            .await?
+            .call(Request::ListDatastore(ListdatastoreRequest {
+                key: Some(key.to_vec()),
+            }))
+            .await
+            .map_err(|e| datastore_error(key, e))?;
+        match response {
+            Response::ListDatastore(r) => Ok(r.datastore),
+            _ => {
+                Err(SmaugError::Datastore("unexpected response to listdatastore".to_owned()).into())
+            }
+        }
+    }
+
+    /// The string stored exactly at `key`, if any.
+    async fn get(&self, key: &[String]) -> Result<Option<String>, Error> {
+        Ok(self
+            .list(key)
+            .await?
+            .into_iter()
+            .find(|record| record.key == key)
+            .and_then(|record| record.string))
+    }
+
+    async fn write(
+        &mut self,
+        key: &[String],
+        value: String,
+        mode: DatastoreMode,
+        generation: Option<u64>,
+    ) -> Result<(), Error> {
+        let value_hash = hash(&value);
+        let response = self
+            .rpc()
+            .await?
+            .call(Request::Datastore(DatastoreRequest {
+                key: key.to_vec(),
+                string: Some(value),
+                hex: None,
+                mode: Some(mode),
+                generation,
+            }))
+            .await
+            .map_err(|e| datastore_error(key, e))?;
+        match response {
+            Response::Datastore(r) => {
+                match r.generation {
+                    Some(generation) => self.generations.insert(key.to_vec(), generation),
+                    None => self.generations.remove(key),
+                };
+                self.written.insert(key.to_vec(), value_hash);
+                Ok(())
+            }
+            _ => Err(SmaugError::Datastore("unexpected response to datastore".to_owned()).into()),
+        }
+    }
+
+    async fn delete(&mut self, key: &[String]) -> Result<(), Error> {
+        self.rpc()
+            .await?
+            .call(Request::DelDatastore(DeldatastoreRequest {
+                key: key.to_vec(),
+                generation: self.generations.get(key).copied(),
+            }))
+            .await
+            .map_err(|e| datastore_error(key, e))?;
+        self.generations.remove(key);
+        self.written.remove(key);
+        Ok(())
+    }
+}
+
+fn hash(value: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The keys and values of wallet `name`, serialized as `wallet`: its transactions,
+/// and its state without them.
+fn records(name: &str, mut wallet: Value) -> [(Vec<String>, Value); 2] {
+    let transactions = match &mut wallet {
+        Value::Object(m) => m.remove("transactions"),
+        _ => None,
+    };
+    [
+        (
+            transactions_key(name),
+            transactions.unwrap_or_else(|| Value::Object(Map::new())),
+        ),
+        (wallet_key(name), wallet),
+    ]
+}
+
+/// Read a wallet record and its transactions, upgrading them to [`SCHEMA_VERSION`].
+fn parse_wallet(wallet: &str, transactions: Option<&str>) -> Result<Parsed, String> {
+    let mut record: Map<String, Value> = serde_json::from_str(wallet).map_err(|e| e.to_string())?;
+    let version = match record.remove("schema_version") {
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or_else(|| format!("invalid schema_version {}", version))?,
+        None => 0,
+    };
+    if version > SCHEMA_VERSION {
+        return Ok(Parsed::Newer(version));
+    }
+    if let Some(transactions) = transactions {
+        let transactions = serde_json::from_str(transactions).map_err(|e| e.to_string())?;
+        record.insert("transactions".to_owned(), transactions);
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(&mut record)?;
+    }
+    let dw = serde_json::from_value(Value::Object(record)).map_err(|e| e.to_string())?;
+    Ok(Parsed::Wallet { dw, version })
+}
+
+fn datastore_error(key: &[String], e: RpcError) -> SmaugError {
+    match e.code {
+        Some(code) if CONFLICT_CODES.contains(&code) => {
+            SmaugError::DatastoreConflict(key.join("/"))
+        }
+        _ => SmaugError::Datastore(format!("{}: {}", key.join("/"), e.message)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rpc_error(code: i32) -> RpcError {
+        RpcError {
+            code: Some(code),
+            message: "datastore error".to_owned(),
+            data: None,
+        }
+    }
+
+    fn wallet(parsed: Parsed) -> (DescriptorWallet, u32) {
+        match parsed {
+            Parsed::Wallet { dw, version } => (dw, version),
+            Parsed::Newer(version) => panic!("unexpected newer record {version}"),
+        }
+    }
+
+    #[test]
+    fn v0_records_get_defaults() {
+        for record in [
+            json!({ "descriptor": "wpkh(a)" }),
+            json!({ "descriptor": "wpkh(a)", "birthday": null, "gap": null }),
+        ] {
+            let (dw, version) = wallet(parse_wallet(&record.to_string(), None).unwrap());
+            assert_eq!(version, 0);
+            assert_eq!(dw.birthday, Some(0));
+            assert_eq!(dw.gap, Some(STOP_GAP as u32));
+        }
+
+        // set values are kept
+        let record = json!({ "descriptor": "wpkh(a)", "birthday": 800000, "gap": 5 });
+        let (dw, _) = wallet(parse_wallet(&record.to_string(), None).unwrap());
+        assert_eq!(dw.birthday, Some(800000));
+        assert_eq!(dw.gap, Some(5));
+    }
+
+    #[test]
+    fn v1_records_forget_unconfirmed_transactions() {
+        let mut record = json!({
+            "transactions": {
+                "a": { "confirmation_time": { "Confirmed": { "height": 1, "time": 2 } } },
+                "b": { "confirmation_time": { "Unconfirmed": { "last_seen": 3 } } },
+                "c": { "confirmation_time": null },
+            }
+        });
+        to_v2(record.as_object_mut().unwrap()).unwrap();
+        let transactions = record["transactions"].as_object().unwrap();
+        assert_eq!(transactions.keys().collect::<Vec<_>>(), ["a"]);
+
+        // wallets without transactions are left alone
+        let mut record = json!({ "descriptor": "wpkh(a)" });
+        to_v2(record.as_object_mut().unwrap()).unwrap();
+        assert_eq!(record, json!({ "descriptor": "wpkh(a)" }));
+    }
+
+    #[test]
+    fn current_records_are_not_migrated() {
+        let record = json!({ "schema_version": SCHEMA_VERSION, "descriptor": "wpkh(a)" });
+        let (dw, version) = wallet(parse_wallet(&record.to_string(), Some("{}")).unwrap());
+        assert_eq!(version, SCHEMA_VERSION);
+        assert_eq!(dw.birthday, None);
+        assert_eq!(dw.gap, None);
+    }
+
+    #[test]
+    fn newer_records_are_skipped() {
+        // a newer smaug may have changed the fields, so they aren't parsed
+        let record = json!({ "schema_version": SCHEMA_VERSION + 1, "descriptor": 1 });
+        assert!(matches!(
+            parse_wallet(&record.to_string(), Some("[")),
+            Ok(Parsed::Newer(version)) if version == SCHEMA_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn unreadable_records_are_errors() {
+        for schema_version in [json!(-1), json!("1"), json!(u64::from(u32::MAX) + 1)] {
+            let record = json!({ "schema_version": schema_version, "descriptor": "wpkh(a)" });
+            assert!(parse_wallet(&record.to_string(), None).is_err());
+        }
+
+        let record = json!({ "schema_version": SCHEMA_VERSION, "descriptor": "wpkh(a)" });
+        assert!(parse_wallet(&record.to_string(), Some("{")).is_err());
+        assert!(parse_wallet(&record.to_string(), Some("[]")).is_err());
+        assert!(parse_wallet("[]", None).is_err());
+    }
+
+    #[test]
+    fn records_split_out_transactions() {
+        let wallet = json!({ "descriptor": "wpkh(a)", "transactions": { "txid": 1 } });
+        let [(tx_key, transactions), (key, wallet)] = records("abc", wallet);
+        assert_eq!(tx_key, transactions_key("abc"));
+        assert_eq!(transactions, json!({ "txid": 1 }));
+        assert_eq!(key, wallet_key("abc"));
+        assert_eq!(wallet, json!({ "descriptor": "wpkh(a)" }));
+
+        // wallets without transactions still get an empty record, replacing stale ones
+        let [(_, transactions), _] = records("abc", json!({ "descriptor": "wpkh(a)" }));
+        assert_eq!(transactions, json!({}));
+    }
+
+    #[test]
+    fn stale_writes_are_conflicts() {
+        let key = wallet_key("abc");
+        for code in CONFLICT_CODES {
+            assert!(matches!(
+                datastore_error(&key, rpc_error(code)),
+                SmaugError::DatastoreConflict(k) if k == "smaug/wallets/abc"
+            ));
+        }
+        assert!(matches!(
+            datastore_error(&key, rpc_error(1200)),
+            SmaugError::Datastore(_)
+        ));
+    }
+}
@@ -0,0 +1,173 @@
+use bdk::bitcoin::{Network, OutPoint};
+use cln_rpc::RpcError;
+use serde_json::{json, Value};
+use std::fmt;
+
+use crate::backend::BackendKind;
+
+/// JSON-RPC error code for params that don't match a method's usage.
+pub const INVALID_PARAMS: i32 = -32602;
+
+/// Errors smaug reports to RPC clients. Each kind has a stable code, listed in the
+/// README, and structured `data` so that clients don't need to parse messages.
+/// Codes stay out of the ranges lightningd uses for its own errors, e.g. 1200-1206
+/// for the datastore, so that clients can tell them apart.
+#[derive(Debug)]
+pub enum SmaugError {
+    /// Params that don't match the method's usage.
+    InvalidParams {
+        message: String,
+        usage: Option<String>,
+    },
+    /// A descriptor that can't be parsed. `field` is `descriptor` or `change_descriptor`.
+    InvalidDescriptor {
+        field: &'static str,
+        reason: String,
+    },
+    /// A descriptor with private keys, which smaug refuses to store.
+    PrivateKeys {
+        field: &'static str,
+    },
+    InvalidBirthday(u64),
+    InvalidGap(u64),
+    /// Networks that should agree but don't, e.g. a mainnet key on testnet.
+    NetworkMismatch {
+        expected: Network,
+        found: Network,
+        /// What `found` comes from.
+        source: String,
+    },
+    UnknownWallet(String),
+    /// `add` of a wallet that is already watched.
+    WalletExists(String),
+    /// An outpoint that isn't one of the wallet's unspent outputs.
+    UnknownCoin {
+        wallet: String,
+        outpoint: OutPoint,
+    },
+    /// A chain source failed to sync a wallet.
+    Backend {
+        backend: BackendKind,
+        message: String,
+    },
+    /// Reading or writing lightningd's datastore failed.
+    Datastore(String),
+    /// A datastore key changed since smaug last read or wrote it.
+    DatastoreConflict(String),
+    /// Opening or writing a wallet's local BDK store failed.
+    Store {
+        wallet: String,
+        message: String,
+    },
+    /// A state smaug never gets into on its own, e.g. a corrupt record.
+    Internal(String),
+}
+
+impl SmaugError {
+    pub fn code(&self) -> i32 {
+        match self {
+            SmaugError::InvalidParams { .. } => INVALID_PARAMS,
+            SmaugError::InvalidDescriptor { .. } => 1100,
+            SmaugError::InvalidBirthday(_) => 1101,
+            SmaugError::InvalidGap(_) => 1102,
+            SmaugError::NetworkMismatch { .. } => 1103,
+            SmaugError::UnknownWallet(_) => 1104,
+            SmaugError::PrivateKeys { .. } => 1105,
+            SmaugError::UnknownCoin { .. } => 1106,
+            SmaugError::WalletExists(_) => 1107,
+            SmaugError::Backend { .. } => 1900,
+            SmaugError::Datastore(_) => 1901,
+            SmaugError::Store { .. } => 1902,
+            SmaugError::DatastoreConflict(_) => 1903,
+            SmaugError::Internal(_) => 1999,
+        }
+    }
+
+    pub fn data(&self) -> Value {
+        match self {
+            SmaugError::InvalidParams { usage, .. } => json!({ "usage": usage }),
+            SmaugError::InvalidDescriptor { field, reason } => {
+                json!({ "field": field, "reason": reason })
+            }
+            SmaugError::PrivateKeys { field } => json!({ "field": field }),
+            SmaugError::InvalidBirthday(birthday) => json!({ "birthday": birthday }),
+            SmaugError::InvalidGap(gap) => json!({ "gap": gap }),
+            SmaugError::NetworkMismatch {
+                expected,
+                found,
+                source,
+            } => json!({ "expected": expected, "found": found, "source": source }),
+            SmaugError::UnknownWallet(name) | SmaugError::WalletExists(name) => {
+                json!({ "descriptor_name": name })
+            }
+            SmaugError::UnknownCoin { wallet, outpoint } => {
+                json!({ "descriptor_name": wallet, "outpoint": outpoint.to_string() })
+            }
+            SmaugError::Backend { backend, .. } => json!({ "backend": backend }),
+            SmaugError::Datastore(_) | SmaugError::Internal(_) => json!({}),
+            SmaugError::Store { wallet, .. } => json!({ "descriptor_name": wallet }),
+            SmaugError::DatastoreConflict(key) => json!({ "key": key }),
+        }
+    }
+}
+
+impl std::error::Error for SmaugError {}
+
+impl fmt::Display for SmaugError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SmaugError::InvalidParams { message, usage } => match usage {
+                Some(usage) => write!(f, "{message}. Usage: {usage}"),
+                None => write!(f, "{message}"),
+            },
+            SmaugError::InvalidDescriptor { field, reason } => {
+                write!(f, "invalid {field}: {reason}")
+            }
+            SmaugError::PrivateKeys { field } => write!(
+                f,
+                "{field} contains private keys. Pass strip_private_keys to watch its public form"
+            ),
+            SmaugError::InvalidBirthday(birthday) => write!(
+                f,
+                "birthday must be between 0 and 4294967295. Received: {birthday}"
+            ),
+            SmaugError::InvalidGap(gap) => {
+                write!(f, "gap must be between 0 and 2147483647. Received: {gap}")
+            }
+            SmaugError::NetworkMismatch {
+                expected,
+                found,
+                source,
+            } => write!(
+                f,
+                "network mismatch: expected {expected}, but {source} is for {found}"
+            ),
+            SmaugError::UnknownWallet(name) => write!(f, "can't find wallet {name}"),
+            SmaugError::WalletExists(name) => write!(f, "wallet {name} is already watched"),
+            SmaugError::UnknownCoin { wallet, outpoint } => {
+                write!(f, "{outpoint} is not an unspent output of wallet {wallet}")
+            }
+            SmaugError::Backend { backend, message } => {
+                write!(f, "error syncing with {backend}: {message}")
+            }
+            SmaugError::Datastore(message) => write!(f, "datastore error: {message}"),
+            SmaugError::Store { wallet, message } => {
+                write!(f, "error with the store of wallet {wallet}: {message}")
+            }
+            SmaugError::DatastoreConflict(key) => {
+                write!(f, "datastore key {key} was modified by someone else")
+            }
+            SmaugError::Internal(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl From<SmaugError> for RpcError {
+    fn from(e: SmaugError) -> Self {
+        RpcError {
+            code: Some(e.code()),
+            message: e.to_string(),
+            data: Some(e.data()),
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use bdk::bitcoin::{OutPoint, Txid};
+use cln_plugin::{Error, Plugin};
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt::Display;
+
+use crate::state::State;
+
+pub const UTXO_DEPOSIT_TAG: &str = "utxo_deposit";
+pub const UTXO_SPENT_TAG: &str = "utxo_spent";
+pub const TX_REORGED_TAG: &str = "tx_reorged";
+pub const ONCHAIN_FEE_TAG: &str = "onchain_fee";
+pub const TX_PENDING_TAG: &str = "tx_pending";
+pub const TX_PENDING_DROPPED_TAG: &str = "tx_pending_dropped";
+
+/// Version of the event payloads. Wallets whose events were sent with an older
+/// version can send them again with `smaug reemit`.
+///
+/// 1: `coin_type` follows the network and `amount_msat` is in millisatoshis.
+pub const EVENTS_VERSION: u32 = 1;
+
+/// An amount in millisatoshis, as bookkeeper expects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct Msat(u64);
+
+impl Msat {
+    pub fn from_sat(sat: u64) -> Self {
+        Msat(sat * 1000)
+    }
+
+    pub fn from_msat(msat: u64) -> Self {
+        Msat(msat)
+    }
+}
+
+fn as_string<T: Display, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+fn option_as_string<T: Display, S: Serializer>(
+    value: &Option<T>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => serializer.collect_str(value),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Payload of a [`UTXO_DEPOSIT_TAG`] notification.
+#[derive(Debug, Clone, Serialize)]
+pub struct UtxoDeposit {
+    pub account: String,
+    pub transfer_from: String,
+    #[serde(serialize_with = "as_string")]
+    pub outpoint: OutPoint,
+    #[serde(serialize_with = "as_string")]
+    pub spending_txid: Txid,
+    pub amount_msat: Msat,
+    pub coin_type: &'static str,
+    #[serde(serialize_with = "as_string")]
+    pub timestamp: u64,
+    #[serde(serialize_with = "as_string")]
+    pub blockheight: u32,
+}
+
+/// Payload of a [`UTXO_SPENT_TAG`] notification.
+#[derive(Debug, Clone, Serialize)]
+pub struct UtxoSpent {
+    pub account: String,
+    #[serde(serialize_with = "as_string")]
+    pub outpoint: OutPoint,
+    #[serde(serialize_with = "as_string")]
+    pub spending_txid: Txid,
+    pub amount_msat: Msat,
+    pub coin_type: &'static str,
+    #[serde(serialize_with = "as_string")]
+    pub timestamp: u64,
+    #[serde(serialize_with = "as_string")]
+    pub blockheight: u32,
+}
+
+/// Payload of an [`ONCHAIN_FEE_TAG`] notification: the miner fee `account` paid
+/// for `txid`.
+#[derive(Debug, Clone, Serialize)]
+pub struct OnchainFee {
+    pub account: String,
+    #[serde(serialize_with = "as_string")]
+    pub txid: Txid,
+    pub amount_msat: Msat,
+    pub coin_type: &'static str,
+    #[serde(serialize_with = "as_string")]
+    pub timestamp: u64,
+    #[serde(serialize_with = "as_string")]
+    pub blockheight: u32,
+}
+
+/// Payload of a [`TX_PENDING_TAG`] notification: a movement seen in the mempool.
+/// It is followed by the usual deposit and spend events once it confirms.
+#[derive(Debug, Clone, Serialize)]
+pub struct TxPending {
+    pub account: String,
+    #[serde(serialize_with = "as_string")]
+    pub txid: Txid,
+    pub received_msat: Msat,
+    pub sent_msat: Msat,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee_msat: Option<Msat>,
+    pub coin_type: &'static str,
+    /// When smaug first saw the transaction.
+    #[serde(serialize_with = "as_string")]
+    pub timestamp: u64,
+}
+
+/// Why a pending transaction will not confirm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DropReason {
+    /// Another transaction spending some of the same outputs replaced it.
+    Replaced,
+    /// It left the mempool without confirming.
+    Evicted,
+}
+
+/// Payload of a [`TX_PENDING_DROPPED_TAG`] notification: a movement previously
+/// announced with [`TX_PENDING_TAG`] that won't confirm.
+#[derive(Debug, Clone, Serialize)]
+pub struct TxPendingDropped {
+    pub account: String,
+    #[serde(serialize_with = "as_string")]
+    pub txid: Txid,
+    pub reason: DropReason,
+    /// The transaction that replaced this one, when known.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "option_as_string"
+    )]
+    pub replaced_by: Option<Txid>,
+    pub coin_type: &'static str,
+}
+
+/// Payload of a [`TX_REORGED_TAG`] notification.
+#[derive(Debug, Clone, Serialize)]
+pub struct TxReorged {
+    pub account: String,
+    #[serde(serialize_with = "as_string")]
+    pub txid: Txid,
+    pub blockheight: u32,
+    /// Left out for transactions recorded before smaug tracked their block.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blockhash: Option<String>,
+}
+
+/// Send a custom notification. It is only queued for lightningd, which doesn't
+/// acknowledge notifications, so an error means it couldn't be queued.
+pub async fn notify(
+    plugin: &Plugin<State>,
+    topic: &str,
+    event: impl Serialize,
+) -> Result<(), Error> {
+    let payload = serde_json::to_value(event)?;
+    log::info!("sending {} notification: {}", topic, payload);
+    plugin
+        .send_custom_notification(topic.to_string(), payload)
+        .await
+        .map_err(|e| anyhow::anyhow!("error sending {} notification: {:#}", topic, e))
+}
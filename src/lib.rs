@@ -1,2 +1,6 @@
+pub mod backend;
+pub mod datastore;
+pub mod error;
+pub mod events;
 pub mod state;
 pub mod wallet;
@@ -4,14 +4,11 @@
 #[macro_use]
 extern crate serde_json;
 
-use bdk::bitcoin::Network;
+use bdk::bitcoin::{Address, Network, OutPoint};
+use bdk::KeychainKind;
 use clap::error::ErrorKind;
 use clap::{arg, CommandFactory, Parser, Subcommand};
-use cln_rpc::model::DatastoreMode;
-use cln_rpc::{
-    model::requests::{DatastoreRequest, ListdatastoreRequest},
-    ClnRpc, Request, Response,
-};
+use cln_rpc::RpcError;
 use home::home_dir;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -19,42 +16,103 @@ use serde_json::Value;
 use std::collections::BTreeMap;
 use std::ffi::OsString;
 use std::fs;
-use std::path::Path;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
 use anyhow::Ok;
-use smaug::wallet::{AddArgs, DescriptorWallet, DATADIR, UTXO_DEPOSIT_TAG, UTXO_SPENT_TAG};
+use smaug::datastore::Datastore;
+use smaug::error::SmaugError;
+use smaug::events::{
+    ONCHAIN_FEE_TAG, TX_PENDING_DROPPED_TAG, TX_PENDING_TAG, TX_REORGED_TAG, UTXO_DEPOSIT_TAG,
+    UTXO_SPENT_TAG,
+};
+use smaug::wallet::{
+    AddArgs, Balance, DescriptorWallet, Direction, TxFilter, TxListItem, UtxoFilter, UtxoListItem,
+    WalletBalance, DATADIR,
+};
 
 use cln_plugin::{anyhow, messages, options, Builder, Error, Plugin};
 use tokio;
 
-use bdk::{bitcoin, TransactionDetails};
+use bdk::bitcoin;
+use smaug::backend::{BackendConfig, BackendKind, BitcoindConfig, SyncMode};
 use smaug::state::{Smaug, State};
 
 #[tokio::main]
 // #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), anyhow::Error> {
     // Create data dir if it does not exist
-    fs::create_dir_all(&home_dir().unwrap().join(DATADIR)).unwrap_or_else(|e| {
+    let home = home_dir().ok_or_else(|| anyhow!("cannot find home directory"))?;
+    fs::create_dir_all(&home.join(DATADIR)).unwrap_or_else(|e| {
         log::error!("Cannot create data dir: {e:?}");
         std::process::exit(1);
     });
-    let builder = Builder::new(tokio::io::stdin(), tokio::io::stdout())
+    let mut builder = Builder::new(tokio::io::stdin(), tokio::io::stdout())
         .option(options::ConfigOption::new(
             "wd_network",
             options::Value::OptString,
             "Which network to use: [bitcoin, testnet, signet, regtest]",
         ))
+        .option(options::ConfigOption::new(
+            "wd_backend",
+            options::Value::OptString,
+            "Chain source to sync wallets with: [esplora, electrum, bitcoind, cln]",
+        ))
+        .option(options::ConfigOption::new(
+            "wd_esplora_url",
+            options::Value::OptString,
+            "Esplora server to sync wallets with, instead of the default public one for the network",
+        ))
+        .option(options::ConfigOption::new(
+            "wd_electrum_url",
+            options::Value::OptString,
+            "Electrum server to sync wallets with, as tcp://host:port or ssl://host:port",
+        ))
+        .option(options::ConfigOption::new(
+            "wd_bitcoind_rpc_url",
+            options::Value::OptString,
+            "bitcoind JSON-RPC URL to scan blocks from",
+        ))
+        .option(options::ConfigOption::new(
+            "wd_bitcoind_rpc_cookie",
+            options::Value::OptString,
+            "Path to bitcoind's RPC cookie file",
+        ))
+        .option(options::ConfigOption::new(
+            "wd_bitcoind_rpc_user",
+            options::Value::OptString,
+            "bitcoind RPC username",
+        ))
+        .option(options::ConfigOption::new(
+            "wd_bitcoind_rpc_password",
+            options::Value::OptString,
+            "bitcoind RPC password",
+        ))
+        .option(options::ConfigOption::new(
+            "wd_bitcoind_mempool",
+            options::Value::OptBoolean,
+            "Scan bitcoind's mempool for unconfirmed transactions on every sync",
+        ))
         .notification(messages::NotificationTopic::new(UTXO_DEPOSIT_TAG))
         .notification(messages::NotificationTopic::new(UTXO_SPENT_TAG))
+        .notification(messages::NotificationTopic::new(ONCHAIN_FEE_TAG))
+        .notification(messages::NotificationTopic::new(TX_PENDING_TAG))
+        .notification(messages::NotificationTopic::new(TX_PENDING_DROPPED_TAG))
+        .notification(messages::NotificationTopic::new(TX_REORGED_TAG))
         .rpcmethod(
             "smaug",
-            "Watch one or more external wallet descriptors and emit notifications when coins are moved",
+            "Watch one or more external wallet descriptors and emit notifications when coins are moved. Also available as smaug-<subcommand> methods",
             parse_command,
         )
         .subscribe("block_added", block_added_handler)
         .dynamic();
+    for method in RPC_METHODS {
+        let callback =
+            move |plugin, v| async move { rpc_method(plugin, method, v).await.map_err(rpc_error) };
+        // the usage goes in the description: this cln-plugin's `rpcmethod` can't
+        // register a usage of its own
+        builder = builder.rpcmethod(method.name, &method.description(), callback);
+    }
     let configured_plugin = if let Some(cp) = builder.configure().await? {
         cp
     } else {
@@ -77,35 +135,88 @@ async fn main() -> Result<(), anyhow::Error> {
         None => configured_plugin.configuration().network,
     }
     .parse::<bitcoin::Network>()
-    .unwrap();
+    .map_err(|e| anyhow!("unsupported network: {}", e))?;
     log::info!("network = {}", network);
-    let rpc_file = configured_plugin.configuration().rpc_file;
-    let p = Path::new(&rpc_file);
-
-    let mut rpc = ClnRpc::new(p).await?;
-    let lds_response = rpc
-        .call(Request::ListDatastore(ListdatastoreRequest {
-            key: Some(vec!["smaug".to_owned()]),
-        }))
-        .await
-        .map_err(|e| anyhow!("Error calling listdatastore: {:?}", e))?;
-    let wallets: BTreeMap<String, DescriptorWallet> = match lds_response {
-        Response::ListDatastore(r) => match r.datastore.is_empty() {
-            true => BTreeMap::new(),
-            false => match &r.datastore[0].string {
-                Some(deserialized) => match serde_json::from_str(&deserialized) {
-                    core::result::Result::Ok(dws) => dws,
-                    core::result::Result::Err(e) => {
-                        log::error!("{}", e);
-                        return Err(e.into());
-                    }
-                },
-                None => BTreeMap::new(),
-            },
-        },
-        _ => panic!(),
+    let cln_network = configured_plugin
+        .configuration()
+        .network
+        .parse::<bitcoin::Network>()
+        .ok();
+    if let Some(cln_network) = cln_network {
+        if cln_network != network {
+            log::warn!(
+                "wd_network {} differs from lightningd's network {}. Wallets can't be added until they agree",
+                network,
+                cln_network
+            );
+        }
+    }
+    let string_option = |name: &str| match configured_plugin.option(name) {
+        Some(options::Value::String(s)) => Some(s),
+        _ => None,
+    };
+    let bitcoind = match string_option("wd_bitcoind_rpc_url") {
+        Some(url) => {
+            let mut config = BitcoindConfig::new(
+                url,
+                string_option("wd_bitcoind_rpc_cookie"),
+                string_option("wd_bitcoind_rpc_user"),
+                string_option("wd_bitcoind_rpc_password"),
+            )?;
+            config.mempool = matches!(
+                configured_plugin.option("wd_bitcoind_mempool"),
+                Some(options::Value::Boolean(true))
+            );
+            Some(config)
+        }
+        None => None,
+    };
+    let kind = match string_option("wd_backend") {
+        Some(kind) => kind.parse::<BackendKind>()?,
+        // setting a bitcoind url alone is enough to use it
+        None if bitcoind.is_some() => BackendKind::Bitcoind,
+        None => BackendKind::Esplora,
+    };
+    let backend = BackendConfig {
+        kind,
+        esplora_url: string_option("wd_esplora_url"),
+        electrum_url: string_option("wd_electrum_url"),
+        bitcoind,
+        rpc_file: configured_plugin.configuration().rpc_file.into(),
+    };
+    // fail at startup rather than on the first block
+    backend.resolve(None, None, None)?;
+    log::info!("backend = {:?}", backend);
+    let mut datastore = Datastore::new(configured_plugin.configuration().rpc_file);
+    let mut wallets = datastore.load().await.map_err(|e| {
+        log::error!("{}", e);
+        e
+    })?;
+    for (name, dw) in wallets.iter_mut() {
+        if dw.strip_private_keys() {
+            log::warn!(
+                "wallet {} was stored with private keys. Only its public form is kept from now on",
+                name
+            );
+        }
+        // wallets added before their network was stored
+        let wallet_network = *dw.network.get_or_insert(network);
+        if wallet_network != network {
+            log::warn!(
+                "wallet {} is on {}, but smaug is configured for {}",
+                name,
+                wallet_network,
+                network
+            );
+        }
+    }
+    let watch_descriptor = Smaug {
+        wallets,
+        network,
+        cln_network,
+        backend,
+        datastore,
     };
-    let watch_descriptor = Smaug { wallets, network };
     let plugin_state = Arc::new(Mutex::new(watch_descriptor.clone()));
     plugin_state.lock().await.network = network;
     let plugin = configured_plugin.start(plugin_state).await?;
@@ -127,13 +238,19 @@ struct Cli {
     command: Option<Commands>,
 }
 
-#[derive(Debug, Subcommand)]
+/// Subcommands of `smaug`. Positional params are parsed by clap, and keyword params
+/// are deserialized from an object naming the subcommand in `subcommand`, e.g.
+/// `{"subcommand": "rm", "descriptor_name": "..."}`.
+#[derive(Debug, Subcommand, Deserialize)]
+#[serde(tag = "subcommand", rename_all = "lowercase", deny_unknown_fields)]
 enum Commands {
     /// Start watching a descriptor wallet
     #[command(alias = "watch")]
+    #[serde(alias = "watch")]
     Add(AddArgs),
     /// Stop watching a descriptor wallet
     #[command(alias = "del", alias = "delete", alias = "remove")]
+    #[serde(alias = "del", alias = "delete", alias = "remove")]
     Rm {
         /// Deterministic name (concatenated checksums) of wallet to delete
         #[arg(short, long)]
@@ -141,65 +258,454 @@ enum Commands {
     },
     /// List descriptor wallets currently being watched
     #[command(alias = "list")]
+    #[serde(alias = "list")]
     Ls,
+    /// Rebuild a wallet's local database and rediscover its history
+    Rescan {
+        /// Deterministic name (concatenated checksums) of wallet to rescan
+        #[arg(short, long)]
+        descriptor_name: String,
+        /// Block height to start scanning from, replacing the wallet's birthday
+        #[arg(short, long)]
+        start_height: Option<u32>,
+        /// Number of empty addresses to scan before giving up, replacing the wallet's gap
+        #[arg(short, long)]
+        gap: Option<u32>,
+    },
+    /// Send the notifications of already recorded transactions again
+    Reemit {
+        /// Deterministic name (concatenated checksums) of wallet to replay. If omitted, every
+        /// wallet whose events were sent by an older version of smaug
+        #[arg(short, long)]
+        descriptor_name: Option<String>,
+    },
+    /// Show the balance of watched wallets, split by keychain
+    Balance {
+        /// Deterministic name (concatenated checksums) of wallet to show. If omitted, every
+        /// wallet, and their total
+        #[arg(short, long)]
+        descriptor_name: Option<String>,
+        /// Block height to compute the balance at, counting only transactions confirmed
+        /// at or below it
+        #[arg(long)]
+        height: Option<u32>,
+    },
+    /// List the transactions of watched wallets, most recent first
+    #[command(name = "listtransactions", alias = "listtxs")]
+    #[serde(alias = "listtxs")]
+    ListTransactions {
+        /// Deterministic name (concatenated checksums) of wallet to list. If omitted, every
+        /// wallet
+        #[arg(short, long)]
+        descriptor_name: Option<String>,
+        /// Only list transactions confirmed at or above this height
+        #[arg(long)]
+        min_height: Option<u32>,
+        /// Only list transactions confirmed at or below this height
+        #[arg(long)]
+        max_height: Option<u32>,
+        /// Only list transactions with a timestamp at or after this unix time
+        #[arg(long)]
+        since: Option<u64>,
+        /// Only list transactions with a timestamp at or before this unix time
+        #[arg(long)]
+        until: Option<u64>,
+        /// Only list transactions moving coins in this direction: [incoming, outgoing]
+        #[arg(long)]
+        direction: Option<Direction>,
+        /// Maximum number of transactions to return
+        #[arg(long)]
+        limit: Option<usize>,
+        /// Number of transactions to skip
+        #[arg(long, default_value_t = 0)]
+        #[serde(default)]
+        offset: usize,
+    },
+    /// List the unspent outputs of watched wallets
+    #[command(name = "listutxos")]
+    ListUtxos {
+        /// Deterministic name (concatenated checksums) of wallet to list. If omitted, every
+        /// wallet
+        #[arg(short, long)]
+        descriptor_name: Option<String>,
+        /// Only list coins worth at least this many sats
+        #[arg(long)]
+        min_value: Option<u64>,
+        /// Only list coins worth at most this many sats
+        #[arg(long)]
+        max_value: Option<u64>,
+        /// Only list coins with at least this many confirmations
+        #[arg(long)]
+        min_depth: Option<u32>,
+        /// Only list coins with at most this many confirmations
+        #[arg(long)]
+        max_depth: Option<u32>,
+    },
+    /// Mark a coin of a watched wallet as frozen
+    Freeze {
+        /// Deterministic name (concatenated checksums) of the wallet owning the coin
+        #[arg(short, long)]
+        descriptor_name: String,
+        /// Coin to freeze, as txid:vout
+        outpoint: OutPoint,
+        /// Unfreeze the coin instead
+        #[arg(long)]
+        #[serde(default)]
+        unfreeze: bool,
+    },
+    /// Attach a label to a coin of a watched wallet
+    Label {
+        /// Deterministic name (concatenated checksums) of the wallet owning the coin
+        #[arg(short, long)]
+        descriptor_name: String,
+        /// Coin to label, as txid:vout
+        outpoint: OutPoint,
+        /// Label to attach. If omitted, the coin's label is removed
+        label: Option<String>,
+    },
+    /// Reveal a new receive address of a watched wallet, so it is included in syncs
+    #[command(name = "newaddress")]
+    NewAddress {
+        /// Deterministic name (concatenated checksums) of the wallet
+        #[arg(short, long)]
+        descriptor_name: String,
+        /// Derive from the change descriptor instead of the external one
+        #[arg(long)]
+        #[serde(default)]
+        change: bool,
+        /// Reveal every address up to this derivation index instead of only the next one
+        #[arg(long)]
+        index: Option<u32>,
+    },
+    /// Show the address of a watched wallet at a derivation index, without revealing it
+    #[command(name = "peekaddress")]
+    PeekAddress {
+        /// Deterministic name (concatenated checksums) of the wallet
+        #[arg(short, long)]
+        descriptor_name: String,
+        /// Derive from the change descriptor instead of the external one
+        #[arg(long)]
+        #[serde(default)]
+        change: bool,
+        /// Derivation index of the address
+        index: u32,
+    },
+    /// Check whether an address belongs to a watched wallet and show its derivation path
+    #[command(name = "verifyaddress")]
+    VerifyAddress {
+        /// Address to look for
+        address: Address,
+        /// Deterministic name (concatenated checksums) of wallet to search. If omitted,
+        /// every wallet
+        #[arg(short, long)]
+        descriptor_name: Option<String>,
+    },
 }
 
 fn to_os_string(v: Value) -> OsString {
-    v.as_str().unwrap().to_owned().into()
+    match v {
+        Value::String(s) => s.into(),
+        v => v.to_string().into(),
+    }
 }
 
 async fn parse_command(
     plugin: Plugin<State>,
     v: serde_json::Value,
 ) -> Result<serde_json::Value, Error> {
-    let arg_vec = match v.clone() {
-        serde_json::Value::Array(a) => a,
+    let command = match v.clone() {
+        serde_json::Value::Array(a) => {
+            match Cli::try_parse_from(a.iter().map(|x| to_os_string(x.clone()))) {
+                core::result::Result::Ok(cli) => cli.command,
+                core::result::Result::Err(e) => match e.kind() {
+                    ErrorKind::DisplayHelp | ErrorKind::DisplayVersion => {
+                        let help_json = json!({
+                            "help_msg": format!("\n{}", e.to_string()),
+                            "format-hint": "simple",
+                        });
+                        log::info!("{}", help_json);
+                        return Ok(json!(help_json));
+                    }
+                    _ => return Err(smaug_invalid_params(e.to_string())),
+                },
+            }
+        }
+        serde_json::Value::Object(m) if m.is_empty() => None,
+        serde_json::Value::Object(_) => match serde_json::from_value::<Commands>(v.clone()) {
+            core::result::Result::Ok(command) => Some(command),
+            core::result::Result::Err(e) => return Err(smaug_invalid_params(e.to_string())),
+        },
         _ => {
-            return Err(anyhow!("only positional args supported. no keyword args."));
+            return Err(smaug_invalid_params(
+                "params must be an array or an object".to_owned(),
+            ))
         }
     };
+    log::info!("command = {:?}", command);
 
-    let matches = Cli::try_parse_from(arg_vec.iter().map(|x| to_os_string(x.clone())));
+    match command {
+        Some(c) => run_command(plugin, c).await.map_err(rpc_error),
+        None => {
+            let help_json = json!({
+                "help_msg": format!("\n{}",  <Cli as CommandFactory>::command().render_help()),
+                "format-hint": "simple",
+            });
+            return Ok(json!(help_json));
+        }
+    }
+}
 
-    match matches {
-        core::result::Result::Ok(cli) => {
-            log::info!("matches = {:?}", cli);
-            match cli.command {
-                Some(c) => match c {
-                    Commands::Add(args) => return smaug(plugin, args).await,
-                    Commands::Rm { descriptor_name } => {
-                        return deletedescriptor(plugin, descriptor_name).await
-                    }
-                    Commands::Ls => return listdescriptors(plugin).await,
-                },
-                None => {
-                    let help_json = json!({
-                        "help_msg": format!("\n{}",  <Cli as CommandFactory>::command().render_help()),
-                        "format-hint": "simple",
-                    });
-                    return Ok(json!(help_json));
-                }
-            }
+/// Invalid params of `smaug`, reported like those of the [`RPC_METHODS`].
+fn smaug_invalid_params(message: String) -> Error {
+    // params and parse errors may echo a descriptor with private keys
+    log::info!("invalid smaug params");
+    rpc_error(
+        SmaugError::InvalidParams {
+            message,
+            usage: None,
         }
-        core::result::Result::Err(e) => match e.kind() {
-            ErrorKind::DisplayHelp | ErrorKind::DisplayVersion => {
-                let help_json = json!({
-                    "help_msg": format!("\n{}", e.to_string()),
-                    "format-hint": "simple",
-                });
-                log::info!("{}", help_json);
-                return Ok(json!(help_json));
-            }
-            _ => {
-                let error_json = json!({
-                    "error_message": e.to_string(),
-                    "format-hint": "simple",
-                });
-                log::info!("args = {:?}", v);
-                log::info!("{}", error_json);
-                return Ok(json!(error_json));
+        .into(),
+    )
+}
+
+/// An RPC method running a single `smaug` subcommand.
+struct RpcMethod {
+    name: &'static str,
+    subcommand: &'static str,
+    /// Params in positional order, optional ones in brackets.
+    usage: &'static str,
+    summary: &'static str,
+}
+
+impl RpcMethod {
+    fn description(&self) -> String {
+        format!("{}. Usage: {}", self.summary, self.usage_line())
+    }
+
+    fn usage_line(&self) -> String {
+        format!("{} {}", self.name, self.usage)
+            .trim_end()
+            .to_owned()
+    }
+
+    fn param_names(&self) -> impl Iterator<Item = &'static str> {
+        self.usage
+            .split_whitespace()
+            .map(|param| param.trim_matches(|c| c == '[' || c == ']'))
+    }
+}
+
+const RPC_METHODS: &[RpcMethod] = &[
+    RpcMethod {
+        name: "smaug-add",
+        subcommand: "add",
+        usage: "descriptor [change_descriptor] [birthday] [gap] [backend] [electrum_url] [esplora_url] [strip_private_keys]",
+        summary: "Start watching a descriptor wallet",
+    },
+    RpcMethod {
+        name: "smaug-rm",
+        subcommand: "rm",
+        usage: "descriptor_name",
+        summary: "Stop watching a descriptor wallet",
+    },
+    RpcMethod {
+        name: "smaug-ls",
+        subcommand: "ls",
+        usage: "",
+        summary: "List descriptor wallets currently being watched",
+    },
+    RpcMethod {
+        name: "smaug-rescan",
+        subcommand: "rescan",
+        usage: "descriptor_name [start_height] [gap]",
+        summary: "Rebuild a wallet's local database and rediscover its history",
+    },
+    RpcMethod {
+        name: "smaug-reemit",
+        subcommand: "reemit",
+        usage: "[descriptor_name]",
+        summary: "Send the notifications of already recorded transactions again",
+    },
+    RpcMethod {
+        name: "smaug-balance",
+        subcommand: "balance",
+        usage: "[descriptor_name] [height]",
+        summary: "Show the balance of watched wallets, split by keychain",
+    },
+    RpcMethod {
+        name: "smaug-listtransactions",
+        subcommand: "listtransactions",
+        usage: "[descriptor_name] [min_height] [max_height] [since] [until] [direction] [limit] [offset]",
+        summary: "List the transactions of watched wallets, most recent first",
+    },
+    RpcMethod {
+        name: "smaug-listutxos",
+        subcommand: "listutxos",
+        usage: "[descriptor_name] [min_value] [max_value] [min_depth] [max_depth]",
+        summary: "List the unspent outputs of watched wallets",
+    },
+    RpcMethod {
+        name: "smaug-freeze",
+        subcommand: "freeze",
+        usage: "descriptor_name outpoint [unfreeze]",
+        summary: "Mark a coin of a watched wallet as frozen",
+    },
+    RpcMethod {
+        name: "smaug-label",
+        subcommand: "label",
+        usage: "descriptor_name outpoint [label]",
+        summary: "Attach a label to a coin of a watched wallet",
+    },
+    RpcMethod {
+        name: "smaug-newaddress",
+        subcommand: "newaddress",
+        usage: "descriptor_name [change] [index]",
+        summary: "Reveal a new receive address of a watched wallet, so it is included in syncs",
+    },
+    RpcMethod {
+        name: "smaug-peekaddress",
+        subcommand: "peekaddress",
+        usage: "descriptor_name index [change]",
+        summary: "Show the address of a watched wallet at a derivation index, without revealing it",
+    },
+    RpcMethod {
+        name: "smaug-verifyaddress",
+        subcommand: "verifyaddress",
+        usage: "address [descriptor_name]",
+        summary: "Check whether an address belongs to a watched wallet and show its derivation path",
+    },
+];
+
+fn invalid_params(method: &RpcMethod, message: String) -> Error {
+    SmaugError::InvalidParams {
+        message,
+        usage: Some(method.usage_line()),
+    }
+    .into()
+}
+
+/// Turn a [`SmaugError`] into an [`RpcError`], so that lightningd replies with
+/// its code and data.
+fn rpc_error(e: Error) -> Error {
+    match e.downcast::<SmaugError>() {
+        core::result::Result::Ok(e) => anyhow!(RpcError::from(e)),
+        core::result::Result::Err(e) => e,
+    }
+}
+
+/// Handle a call to one of the [`RPC_METHODS`]. Positional params are named after
+/// the method's usage, then both forms go through the same typed [`Commands`] as
+/// keyword params of `smaug`.
+async fn rpc_method(
+    plugin: Plugin<State>,
+    method: &'static RpcMethod,
+    v: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let command = parse_params(method, v)?;
+    log::info!("{} command = {:?}", method.name, command);
+    run_command(plugin, command).await
+}
+
+/// Turn the params of a call to `method` into its subcommand. Null positional params
+/// are left out, as if they were omitted.
+fn parse_params(method: &RpcMethod, v: serde_json::Value) -> Result<Commands, Error> {
+    let mut params = match v {
+        serde_json::Value::Object(m) => m,
+        serde_json::Value::Array(a) => {
+            if a.len() > method.param_names().count() {
+                return Err(invalid_params(
+                    method,
+                    format!("too many params: {}", a.len()),
+                ));
             }
-        },
+            method
+                .param_names()
+                .zip(a)
+                .filter(|(_, value)| !value.is_null())
+                .map(|(name, value)| (name.to_owned(), value))
+                .collect()
+        }
+        _ => return Err(invalid_params(method, format!("unexpected params: {}", v))),
+    };
+    params.insert("subcommand".to_owned(), json!(method.subcommand));
+    serde_json::from_value::<Commands>(serde_json::Value::Object(params))
+        .map_err(|e| invalid_params(method, e.to_string()))
+}
+
+/// Run a subcommand, whether it came as positional or keyword params.
+async fn run_command(plugin: Plugin<State>, c: Commands) -> Result<serde_json::Value, Error> {
+    match c {
+        Commands::Add(args) => smaug(plugin, args).await,
+        Commands::Rm { descriptor_name } => deletedescriptor(plugin, descriptor_name).await,
+        Commands::Ls => listdescriptors(plugin).await,
+        Commands::Rescan {
+            descriptor_name,
+            start_height,
+            gap,
+        } => rescan(plugin, descriptor_name, start_height, gap).await,
+        Commands::Reemit { descriptor_name } => reemit(plugin, descriptor_name).await,
+        Commands::Balance {
+            descriptor_name,
+            height,
+        } => balance(plugin, descriptor_name, height).await,
+        Commands::ListTransactions {
+            descriptor_name,
+            min_height,
+            max_height,
+            since,
+            until,
+            direction,
+            limit,
+            offset,
+        } => {
+            let filter = TxFilter {
+                min_height,
+                max_height,
+                since,
+                until,
+                direction,
+            };
+            listtransactions(plugin, descriptor_name, filter, limit, offset).await
+        }
+        Commands::ListUtxos {
+            descriptor_name,
+            min_value,
+            max_value,
+            min_depth,
+            max_depth,
+        } => {
+            let filter = UtxoFilter {
+                min_value,
+                max_value,
+                min_depth,
+                max_depth,
+            };
+            listutxos(plugin, descriptor_name, filter).await
+        }
+        Commands::Freeze {
+            descriptor_name,
+            outpoint,
+            unfreeze,
+        } => freeze(plugin, descriptor_name, outpoint, !unfreeze).await,
+        Commands::Label {
+            descriptor_name,
+            outpoint,
+            label: text,
+        } => label(plugin, descriptor_name, outpoint, text).await,
+        Commands::NewAddress {
+            descriptor_name,
+            change,
+            index,
+        } => newaddress(plugin, descriptor_name, keychain(change), index).await,
+        Commands::PeekAddress {
+            descriptor_name,
+            change,
+            index,
+        } => peekaddress(plugin, descriptor_name, keychain(change), index).await,
+        Commands::VerifyAddress {
+            address,
+            descriptor_name,
+        } => verifyaddress(plugin, address, descriptor_name).await,
     }
 }
 
@@ -208,53 +714,29 @@ async fn smaug(
     // v: serde_json::Value,
     args: AddArgs,
 ) -> Result<serde_json::Value, Error> {
-    let mut dw = DescriptorWallet::from_args(args, plugin.state().lock().await.network.clone())
-        .map_err(|e| anyhow!("error parsing args: {}", e))?;
+    // held until the wallet is added, so that neither a concurrent add nor a block
+    // sync sends its history again
+    log::info!("waiting for wallet lock");
+    let state = &mut *plugin.state().lock().await;
+    state.check_network()?;
+    let mut dw = DescriptorWallet::from_args(args, state.network)?;
     // dw.network = );
     log::info!("params = {:?}", dw);
-
-    let wallet = dw.fetch_wallet().await?;
-    let bdk_transactions_iter = wallet.transactions();
-    let mut transactions = Vec::<TransactionDetails>::new();
-    for bdk_transaction in bdk_transactions_iter {
-        log::info!("BDK transaction = {:?}", bdk_transaction.node.tx);
-        transactions.push(wallet.get_tx(bdk_transaction.node.txid, true).unwrap());
-    }
-
-    if transactions.len() > 0 {
-        log::info!("found some transactions: {:?}", transactions);
-        let new_txs = dw.update_transactions(transactions);
-        if new_txs.len() > 0 {
-            for tx in new_txs {
-                log::info!("new tx found!: {:?}", tx);
-                dw.send_notifications_for_tx(&plugin, &wallet, tx).await?;
-            }
-        } else {
-            log::info!("no new txs this time");
-        }
+    let name = dw.get_name()?;
+    if state.wallets.contains_key(&name) {
+        return Err(SmaugError::WalletExists(name).into());
     }
-    log::info!("waiting for wallet lock");
-    plugin.state().lock().await.add_descriptor_wallet(&dw)?;
-
-    let wallets_str = json!(plugin.state().lock().await.wallets).to_string();
-    let rpc_file = plugin.configuration().rpc_file;
-    let p = Path::new(&rpc_file);
-
-    let mut rpc = ClnRpc::new(p).await?;
-    let _ds_response = rpc
-        .call(Request::Datastore(DatastoreRequest {
-            key: vec!["smaug".to_owned()],
-            string: Some(wallets_str),
-            hex: None,
-            mode: Some(DatastoreMode::CREATE_OR_REPLACE),
-            generation: None,
-        }))
-        .await
-        .map_err(|e| anyhow!("Error calling listdatastore: {:?}", e))?;
+
+    let backend = state.backend_for(&dw)?;
+    dw.sync_and_notify(&plugin, &backend, SyncMode::Full)
+        .await?;
+    // only watched once it is stored, so that a failed add leaves nothing behind
+    state.datastore.save_wallet(&name, &dw).await?;
+    state.add_descriptor_wallet(&dw)?;
     log::info!("wallet added");
     let message = format!(
         "Wallet with deterministic name {} successfully added",
-        &dw.get_name()?
+        &name
     );
     log::info!("returning");
     Ok(json!(message))
@@ -266,7 +748,11 @@ struct ListResponseItem {
     pub change_descriptor: Option<String>,
     pub birthday: Option<u32>,
     pub gap: Option<u32>,
+    pub last_synced: Option<u32>,
     pub network: Option<Network>,
+    pub backend: Option<BackendKind>,
+    pub electrum_url: Option<String>,
+    pub esplora_url: Option<String>,
 }
 
 async fn listdescriptors(
@@ -283,7 +769,11 @@ async fn listdescriptors(
                 change_descriptor: wallet.change_descriptor.clone(),
                 birthday: wallet.birthday.clone(),
                 gap: wallet.gap.clone(),
+                last_synced: wallet.last_synced,
                 network: wallet.network.clone(),
+                backend: wallet.backend,
+                electrum_url: wallet.electrum_url.clone(),
+                esplora_url: wallet.esplora_url.clone(),
             },
         );
     }
@@ -295,61 +785,409 @@ async fn deletedescriptor(
     // v: serde_json::Value,
     descriptor_name: String,
 ) -> Result<serde_json::Value, Error> {
-    let wallets = &mut plugin.state().lock().await.wallets;
-    let _removed_item: Option<DescriptorWallet>;
-    if wallets.contains_key(&descriptor_name) {
-        _removed_item = wallets.remove(&descriptor_name);
-        let rpc_file = plugin.configuration().rpc_file;
-        let p = Path::new(&rpc_file);
-
-        let mut rpc = ClnRpc::new(p).await?;
-        let _ds_response = rpc
-            .call(Request::Datastore(DatastoreRequest {
-                key: vec!["smaug".to_owned()],
-                string: Some(json!(wallets).to_string()),
-                hex: None,
-                mode: Some(DatastoreMode::CREATE_OR_REPLACE),
-                generation: None,
-            }))
-            .await
-            .map_err(|e| anyhow!("Error calling listdatastore: {:?}", e))?;
+    let state = &mut *plugin.state().lock().await;
+    if state.wallets.contains_key(&descriptor_name) {
+        state.datastore.delete_wallet(&descriptor_name).await?;
+        state.wallets.remove(&descriptor_name);
     } else {
-        return Err(anyhow!("can't find wallet {}", descriptor_name));
+        return Err(SmaugError::UnknownWallet(descriptor_name.clone()).into());
     }
 
     Ok(json!(format!("Deleted wallet: {}", descriptor_name)))
 }
 
+async fn rescan(
+    plugin: Plugin<State>,
+    descriptor_name: String,
+    start_height: Option<u32>,
+    gap: Option<u32>,
+) -> Result<serde_json::Value, Error> {
+    // hold the state lock so that no block sync touches the wallet meanwhile
+    let state = &mut *plugin.state().lock().await;
+    let mut dw = match state.wallets.get(&descriptor_name) {
+        Some(dw) => dw.clone(),
+        None => return Err(SmaugError::UnknownWallet(descriptor_name.clone()).into()),
+    };
+    if start_height.is_some() {
+        dw.birthday = start_height;
+    }
+    if let Some(gap) = gap {
+        dw = dw.with_gap(gap as u64)?;
+    }
+    let backend = state.backend_for(&dw)?;
+    // persisted before the store is deleted, so that a rescan that fails or is
+    // interrupted is retried as a full scan rather than an incremental sync of an
+    // empty store
+    dw.last_synced = None;
+    state.datastore.save_wallet(&descriptor_name, &dw).await?;
+    state.add_descriptor_wallet(&dw)?;
+    dw.reset_store()?;
+    let synced = dw.sync_and_notify(&plugin, &backend, SyncMode::Full).await;
+    // keep whatever was notified, even if the sync failed part way
+    state.datastore.save_wallet(&descriptor_name, &dw).await?;
+    state.add_descriptor_wallet(&dw)?;
+    synced?;
+    Ok(json!(format!(
+        "Rescanned wallet {} from height {}",
+        descriptor_name,
+        dw.birthday.unwrap_or(0)
+    )))
+}
+
+async fn reemit(
+    plugin: Plugin<State>,
+    descriptor_name: Option<String>,
+) -> Result<serde_json::Value, Error> {
+    let state = &mut *plugin.state().lock().await;
+    if let Some(name) = &descriptor_name {
+        if !state.wallets.contains_key(name) {
+            return Err(SmaugError::UnknownWallet(name.clone()).into());
+        }
+    }
+    let mut result = BTreeMap::<String, usize>::new();
+    for (wallet_name, dw) in state.wallets.iter_mut() {
+        let selected = match &descriptor_name {
+            Some(name) => name == wallet_name,
+            None => dw.needs_reemit(),
+        };
+        if selected {
+            result.insert(wallet_name.clone(), dw.reemit(&plugin).await?);
+            state.datastore.save_wallet(wallet_name, dw).await?;
+        }
+    }
+    Ok(json!({ "reemitted": result }))
+}
+
+async fn balance(
+    plugin: Plugin<State>,
+    descriptor_name: Option<String>,
+    height: Option<u32>,
+) -> Result<serde_json::Value, Error> {
+    let wallets = &plugin.state().lock().await.wallets;
+    if let Some(name) = &descriptor_name {
+        if !wallets.contains_key(name) {
+            return Err(SmaugError::UnknownWallet(name.clone()).into());
+        }
+    }
+    let mut result = BTreeMap::<String, WalletBalance>::new();
+    let mut total = Balance::default();
+    for (wallet_name, dw) in wallets {
+        if descriptor_name
+            .as_ref()
+            .is_some_and(|name| name != wallet_name)
+        {
+            continue;
+        }
+        let wallet_balance = dw.balance(height)?;
+        total.add(&wallet_balance.balance);
+        result.insert(wallet_name.clone(), wallet_balance);
+    }
+    Ok(json!({ "wallets": result, "total": total }))
+}
+
+async fn listtransactions(
+    plugin: Plugin<State>,
+    descriptor_name: Option<String>,
+    filter: TxFilter,
+    limit: Option<usize>,
+    offset: usize,
+) -> Result<serde_json::Value, Error> {
+    let wallets = &plugin.state().lock().await.wallets;
+    if let Some(name) = &descriptor_name {
+        if !wallets.contains_key(name) {
+            return Err(SmaugError::UnknownWallet(name.clone()).into());
+        }
+    }
+    let mut transactions = Vec::<TxListItem>::new();
+    for (wallet_name, dw) in wallets {
+        if descriptor_name
+            .as_ref()
+            .is_some_and(|name| name != wallet_name)
+        {
+            continue;
+        }
+        transactions.extend(dw.list_transactions(&filter)?);
+    }
+    // unconfirmed first, then by descending height
+    transactions.sort_by(|a, b| {
+        let key = |tx: &TxListItem| (tx.height.unwrap_or(u32::MAX), tx.timestamp);
+        key(b).cmp(&key(a))
+    });
+    let total = transactions.len();
+    let transactions = transactions
+        .into_iter()
+        .skip(offset)
+        .take(limit.unwrap_or(usize::MAX))
+        .collect::<Vec<_>>();
+    Ok(json!({ "transactions": transactions, "total": total }))
+}
+
+async fn listutxos(
+    plugin: Plugin<State>,
+    descriptor_name: Option<String>,
+    filter: UtxoFilter,
+) -> Result<serde_json::Value, Error> {
+    let wallets = &plugin.state().lock().await.wallets;
+    if let Some(name) = &descriptor_name {
+        if !wallets.contains_key(name) {
+            return Err(SmaugError::UnknownWallet(name.clone()).into());
+        }
+    }
+    let mut utxos = Vec::<UtxoListItem>::new();
+    for (wallet_name, dw) in wallets {
+        if descriptor_name
+            .as_ref()
+            .is_some_and(|name| name != wallet_name)
+        {
+            continue;
+        }
+        utxos.extend(dw.list_utxos(&filter)?);
+    }
+    let total_sat = utxos.iter().map(|utxo| utxo.value_sat).sum::<u64>();
+    Ok(json!({ "utxos": utxos, "total_sat": total_sat }))
+}
+
+async fn freeze(
+    plugin: Plugin<State>,
+    descriptor_name: String,
+    outpoint: OutPoint,
+    frozen: bool,
+) -> Result<serde_json::Value, Error> {
+    let state = &mut *plugin.state().lock().await;
+    match state.wallets.get_mut(&descriptor_name) {
+        Some(dw) => {
+            dw.set_frozen(outpoint, frozen)?;
+            state.datastore.save_wallet(&descriptor_name, dw).await?;
+        }
+        None => return Err(SmaugError::UnknownWallet(descriptor_name.clone()).into()),
+    }
+    Ok(json!({ "outpoint": outpoint, "frozen": frozen }))
+}
+
+async fn label(
+    plugin: Plugin<State>,
+    descriptor_name: String,
+    outpoint: OutPoint,
+    label: Option<String>,
+) -> Result<serde_json::Value, Error> {
+    let state = &mut *plugin.state().lock().await;
+    match state.wallets.get_mut(&descriptor_name) {
+        Some(dw) => {
+            dw.set_label(outpoint, label.clone())?;
+            state.datastore.save_wallet(&descriptor_name, dw).await?;
+        }
+        None => return Err(SmaugError::UnknownWallet(descriptor_name.clone()).into()),
+    }
+    Ok(json!({ "outpoint": outpoint, "label": label }))
+}
+
+fn keychain(change: bool) -> KeychainKind {
+    if change {
+        KeychainKind::Internal
+    } else {
+        KeychainKind::External
+    }
+}
+
+async fn newaddress(
+    plugin: Plugin<State>,
+    descriptor_name: String,
+    keychain: KeychainKind,
+    index: Option<u32>,
+) -> Result<serde_json::Value, Error> {
+    let wallets = &plugin.state().lock().await.wallets;
+    match wallets.get(&descriptor_name) {
+        Some(dw) => Ok(json!(dw.new_address(keychain, index)?)),
+        None => Err(SmaugError::UnknownWallet(descriptor_name.clone()).into()),
+    }
+}
+
+async fn peekaddress(
+    plugin: Plugin<State>,
+    descriptor_name: String,
+    keychain: KeychainKind,
+    index: u32,
+) -> Result<serde_json::Value, Error> {
+    let wallets = &plugin.state().lock().await.wallets;
+    match wallets.get(&descriptor_name) {
+        Some(dw) => Ok(json!(dw.peek_address(keychain, index)?)),
+        None => Err(SmaugError::UnknownWallet(descriptor_name.clone()).into()),
+    }
+}
+
+async fn verifyaddress(
+    plugin: Plugin<State>,
+    address: Address,
+    descriptor_name: Option<String>,
+) -> Result<serde_json::Value, Error> {
+    let wallets = &plugin.state().lock().await.wallets;
+    if let Some(name) = &descriptor_name {
+        if !wallets.contains_key(name) {
+            return Err(SmaugError::UnknownWallet(name.clone()).into());
+        }
+    }
+    for (wallet_name, dw) in wallets {
+        if descriptor_name
+            .as_ref()
+            .is_some_and(|name| name != wallet_name)
+        {
+            continue;
+        }
+        if let Some(found) = dw.verify_address(&address)? {
+            return Ok(json!({
+                "address": address.to_string(),
+                "found": true,
+                "wallet": wallet_name,
+                "keychain": found.address.keychain,
+                "index": found.address.index,
+                "keys": found.keys,
+            }));
+        }
+    }
+    Ok(json!({ "address": address.to_string(), "found": false }))
+}
+
 async fn block_added_handler(plugin: Plugin<State>, v: serde_json::Value) -> Result<(), Error> {
     log::info!("Got a block_added notification: {}", v);
     log::info!("Smaug state!!! {:?}", plugin.state().lock().await.wallets);
 
-    let descriptor_wallets = &mut plugin.state().lock().await.wallets;
-    for (_dw_desc, dw) in descriptor_wallets.iter_mut() {
-        let wallet = dw.fetch_wallet().await?;
-        let bdk_transactions_iter = wallet.transactions();
-        let mut transactions = Vec::<TransactionDetails>::new();
-        for bdk_transaction in bdk_transactions_iter {
-            log::info!("BDK transaction = {:?}", bdk_transaction.node.tx);
-            transactions.push(wallet.get_tx(bdk_transaction.node.txid, true).unwrap());
-        }
-
-        if transactions.len() > 0 {
-            log::info!(
-                "found some new transactions in new block! : {:?}",
-                transactions
-            );
-            let new_txs = dw.update_transactions(transactions);
-            if new_txs.len() > 0 {
-                for tx in new_txs {
-                    dw.send_notifications_for_tx(&plugin, &wallet, tx).await?;
-                }
-            } else {
-                log::info!("no new txs this time");
+    let state = &mut *plugin.state().lock().await;
+    let backend_config = state.backend.clone();
+    // a failing wallet is retried on the next block, and doesn't hold up the others
+    let mut failed = Vec::new();
+    for (dw_name, dw) in state.wallets.iter_mut() {
+        let backend = match backend_config.resolve(
+            dw.backend,
+            dw.esplora_url.as_ref(),
+            dw.electrum_url.as_ref(),
+        ) {
+            Ok(backend) => backend,
+            Err(e) => {
+                log::error!("Error resolving the backend of wallet {}: {:#}", dw_name, e);
+                failed.push(dw_name.clone());
+                continue;
             }
-        } else {
-            log::info!("found no transactions");
+        };
+        let synced = dw
+            .sync_and_notify(&plugin, &backend, SyncMode::Incremental)
+            .await;
+        if let Err(e) = &synced {
+            log::error!("Error syncing wallet {}: {:#}", dw_name, e);
+        }
+        // persist whatever was notified, even if the sync failed part way, so that
+        // it isn't notified again after a restart
+        let saved = state.datastore.save_wallet(dw_name, dw).await;
+        if let Err(e) = &saved {
+            log::error!("Error saving wallet {}: {:#}", dw_name, e);
         }
+        if synced.is_err() || saved.is_err() {
+            failed.push(dw_name.clone());
+        }
+    }
+    if !failed.is_empty() {
+        return Err(anyhow!("failed to sync wallets: {}", failed.join(", ")));
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smaug::error::INVALID_PARAMS;
+
+    fn method(name: &str) -> &'static RpcMethod {
+        RPC_METHODS.iter().find(|m| m.name == name).unwrap()
+    }
+
+    fn error_code(e: Error) -> i32 {
+        e.downcast::<SmaugError>().unwrap().code()
+    }
+
+    #[test]
+    fn positional_params_follow_usage() {
+        let command = parse_params(method("smaug-rescan"), json!(["abc", 100, 20])).unwrap();
+        assert!(matches!(
+            command,
+            Commands::Rescan { descriptor_name, start_height: Some(100), gap: Some(20) }
+                if descriptor_name == "abc"
+        ));
+
+        // trailing optional params can be omitted
+        let command = parse_params(method("smaug-rescan"), json!(["abc"])).unwrap();
+        assert!(matches!(
+            command,
+            Commands::Rescan {
+                start_height: None,
+                gap: None,
+                ..
+            }
+        ));
+
+        let command = parse_params(method("smaug-ls"), json!([])).unwrap();
+        assert!(matches!(command, Commands::Ls));
+    }
+
+    #[test]
+    fn null_positional_params_are_omitted() {
+        let command = parse_params(method("smaug-rescan"), json!(["abc", null, 20])).unwrap();
+        assert!(matches!(
+            command,
+            Commands::Rescan {
+                start_height: None,
+                gap: Some(20),
+                ..
+            }
+        ));
+
+        let command = parse_params(
+            method("smaug-listtransactions"),
+            json!([null, null, null, null, null, null, 5, 10]),
+        )
+        .unwrap();
+        assert!(matches!(
+            command,
+            Commands::ListTransactions {
+                descriptor_name: None,
+                limit: Some(5),
+                offset: 10,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn keyword_params_match_positional() {
+        let command = parse_params(
+            method("smaug-rescan"),
+            json!({ "descriptor_name": "abc", "gap": 20 }),
+        )
+        .unwrap();
+        assert!(matches!(
+            command,
+            Commands::Rescan { descriptor_name, start_height: None, gap: Some(20) }
+                if descriptor_name == "abc"
+        ));
+    }
+
+    #[test]
+    fn bad_params_are_invalid() {
+        let too_many = parse_params(method("smaug-rm"), json!(["abc", "def"])).unwrap_err();
+        assert_eq!(error_code(too_many), INVALID_PARAMS);
+
+        let missing = parse_params(method("smaug-rm"), json!([])).unwrap_err();
+        assert_eq!(error_code(missing), INVALID_PARAMS);
+
+        let unknown = parse_params(
+            method("smaug-rm"),
+            json!({ "descriptor_name": "abc", "foo": 1 }),
+        )
+        .unwrap_err();
+        assert_eq!(error_code(unknown), INVALID_PARAMS);
+
+        let wrong_type = parse_params(method("smaug-rescan"), json!(["abc", "tip"])).unwrap_err();
+        assert_eq!(error_code(wrong_type), INVALID_PARAMS);
+
+        let not_params = parse_params(method("smaug-rm"), json!("abc")).unwrap_err();
+        assert_eq!(error_code(not_params), INVALID_PARAMS);
+    }
+}
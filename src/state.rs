@@ -3,7 +3,12 @@ use std::{collections::BTreeMap, sync::Arc};
 use bdk::bitcoin;
 use tokio::sync::Mutex;
 
-use crate::wallet::DescriptorWallet;
+use crate::{
+    backend::{Backend, BackendConfig},
+    datastore::Datastore,
+    error::SmaugError,
+    wallet::DescriptorWallet,
+};
 
 pub type State = Arc<Mutex<Smaug>>;
 
@@ -12,6 +17,12 @@ pub struct Smaug {
     /// A collection of descriptors the plugin is watching.
     pub wallets: BTreeMap<String, DescriptorWallet>,
     pub network: bitcoin::Network,
+    /// lightningd's network, when it is one BDK knows. `network` must match it.
+    pub cln_network: Option<bitcoin::Network>,
+    /// Chain source settings used to sync the watched wallets.
+    pub backend: BackendConfig,
+    /// Where the watched wallets are persisted.
+    pub datastore: Datastore,
 }
 
 impl Smaug {
@@ -19,6 +30,9 @@ impl Smaug {
         Self {
             wallets: BTreeMap::new(),
             network: bitcoin::Network::Bitcoin,
+            cln_network: None,
+            backend: BackendConfig::default(),
+            datastore: Datastore::default(),
         }
     }
 
@@ -29,4 +43,33 @@ impl Smaug {
         self.wallets.insert(wallet.get_name()?, wallet.clone());
         Ok(())
     }
+
+    /// Check that the configured network is lightningd's.
+    pub fn check_network(&self) -> Result<(), SmaugError> {
+        match self.cln_network {
+            Some(cln_network) if cln_network != self.network => Err(SmaugError::NetworkMismatch {
+                expected: cln_network,
+                found: self.network,
+                source: "wd_network".to_owned(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// The chain source to sync `wallet` with.
+    pub fn backend_for(&self, wallet: &DescriptorWallet) -> Result<Backend, anyhow::Error> {
+        self.backend
+            .resolve(
+                wallet.backend,
+                wallet.esplora_url.as_ref(),
+                wallet.electrum_url.as_ref(),
+            )
+            .map_err(|e| {
+                SmaugError::Backend {
+                    backend: wallet.backend.unwrap_or(self.backend.kind),
+                    message: e.to_string(),
+                }
+                .into()
+            })
+    }
 }
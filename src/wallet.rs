@@ -1,10 +1,17 @@
 use bdk::{
     bitcoin::{
+        blockdata::constants::COINBASE_MATURITY,
         secp256k1::{All, Secp256k1},
-        Network, Txid,
+        Address, BlockHash, Network, OutPoint, Transaction, TxOut, Txid,
     },
-    chain::{keychain::LocalChangeSet, ConfirmationTime, ConfirmationTimeAnchor},
-    wallet::wallet_name_from_descriptor,
+    chain::{
+        keychain::{LocalChangeSet, LocalUpdate},
+        local_chain::LocalChain,
+        BlockId, ConfirmationTime, ConfirmationTimeAnchor,
+    },
+    descriptor::IntoWalletDescriptor,
+    miniscript::{Descriptor, DescriptorPublicKey, ForEachKey},
+    wallet::{wallet_name_from_descriptor, AddressIndex, AddressInfo},
     KeychainKind, TransactionDetails, Wallet,
 };
 use bdk_esplora::{esplora_client, EsploraAsyncExt};
@@ -14,40 +21,35 @@ use cln_plugin::{Error, Plugin};
 use home::home_dir;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{collections::BTreeMap, fmt, io::Write};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fmt, fs, io, iter,
+    path::PathBuf,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use crate::state::State;
+use crate::{
+    backend::{
+        bitcoind_outputs, electrum_outputs, incremental_spks, outputs_of, sync_bitcoind, sync_cln,
+        sync_electrum, unconfirmed_txids, Backend, BackendKind, SyncMode, SYNC_LOOKAHEAD,
+    },
+    error::SmaugError,
+    events::{
+        notify, DropReason, Msat, OnchainFee, TxPending, TxPendingDropped, TxReorged, UtxoDeposit,
+        UtxoSpent, EVENTS_VERSION, ONCHAIN_FEE_TAG, TX_PENDING_DROPPED_TAG, TX_PENDING_TAG,
+        TX_REORGED_TAG, UTXO_DEPOSIT_TAG, UTXO_SPENT_TAG,
+    },
+    state::State,
+};
 
 pub const DATADIR: &str = ".smaug";
-const STOP_GAP: usize = 50;
+/// Default gap limit.
+pub const STOP_GAP: usize = 50;
 const PARALLEL_REQUESTS: usize = 5;
-
-pub const UTXO_DEPOSIT_TAG: &str = "utxo_deposit";
-pub const UTXO_SPENT_TAG: &str = "utxo_spent";
-
-/// Errors related to the `smaug` command.
-#[derive(Debug)]
-pub enum WatchError {
-    InvalidDescriptor(String),
-    InvalidChangeDescriptor(String),
-    InvalidBirthday(String),
-    InvalidGap(String),
-    InvalidFormat(String),
-}
-
-impl std::error::Error for WatchError {}
-
-impl std::fmt::Display for WatchError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            WatchError::InvalidDescriptor(x) => write!(f, "{x}"),
-            WatchError::InvalidChangeDescriptor(x) => write!(f, "{x}"),
-            WatchError::InvalidBirthday(x) => write!(f, "{x}"),
-            WatchError::InvalidGap(x) => write!(f, "{x}"),
-            WatchError::InvalidFormat(x) => write!(f, "{x}"),
-        }
-    }
-}
+/// How long a transaction may stay pending before it's considered evicted, like
+/// bitcoind's default `-mempoolexpiry`.
+const MEMPOOL_EXPIRY_SECS: u64 = 14 * 24 * 60 * 60;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "snake_case")]
@@ -60,20 +62,365 @@ pub enum WDNetwork {
     Mutinynet,
 }
 
-pub fn get_network_url(network: &str) -> String {
+/// Default Esplora URL for a network, used when neither `wd_esplora_url` nor a
+/// per-wallet `esplora_url` is set.
+pub fn get_network_url(network: &str) -> Result<String, Error> {
     match network {
-        "bitcoin" | "mainnet" => "https://blockstream.info/api".to_owned(),
-        "testnet" => "https://blockstream.info/testnet/api".to_owned(),
-        "regtest" | "mutinynet" => "https://mutinynet.com/api".to_owned(),
-        "signet" => "https://mempool.space/signet/api".to_owned(),
-        _ => {
-            panic!();
+        "bitcoin" | "mainnet" => Ok("https://blockstream.info/api".to_owned()),
+        "testnet" => Ok("https://blockstream.info/testnet/api".to_owned()),
+        "regtest" | "mutinynet" => Ok("https://mutinynet.com/api".to_owned()),
+        "signet" => Ok("https://mempool.space/signet/api".to_owned()),
+        _ => Err(anyhow::anyhow!(
+            "no default esplora url for network {network}. Set wd_esplora_url or esplora_url"
+        )),
+    }
+}
+
+/// Coin type of bookkeeper events for a network, matching the one lightningd
+/// uses for its own coin movements.
+pub fn coin_type(network: Network) -> &'static str {
+    match network {
+        Network::Bitcoin => "bc",
+        Network::Testnet => "tb",
+        Network::Signet => "tbs",
+        Network::Regtest => "bcrt",
+    }
+}
+
+/// Public form of `descriptor`, and whether it had private keys. `None` if it can't
+/// be parsed.
+fn public_descriptor(descriptor: &str) -> Option<(String, bool)> {
+    let secp = Secp256k1::<All>::new();
+    let (public, keymap) =
+        Descriptor::<DescriptorPublicKey>::parse_descriptor(&secp, descriptor).ok()?;
+    Some((public.to_string(), !keymap.is_empty()))
+}
+
+fn has_private_keys(descriptor: &str) -> bool {
+    public_descriptor(descriptor).is_some_and(|(_, private)| private)
+}
+
+/// `descriptor` in a form that is safe to log.
+pub fn redact(descriptor: &str) -> String {
+    match public_descriptor(descriptor) {
+        Some((public, _)) => public,
+        None => "<unparseable descriptor>".to_owned(),
+    }
+}
+
+/// Network of the first extended key of `descriptor`, if it has one. Single keys
+/// don't tell.
+fn key_network(descriptor: &str) -> Option<Network> {
+    let secp = Secp256k1::<All>::new();
+    let (descriptor, _) =
+        Descriptor::<DescriptorPublicKey>::parse_descriptor(&secp, descriptor).ok()?;
+    let mut network = None;
+    descriptor.for_each_key(|key| {
+        if let DescriptorPublicKey::XPub(xpub) = key {
+            network = Some(xpub.xkey.network);
+        }
+        network.is_none()
+    });
+    network
+}
+
+/// Miner fee of `tx` in sats: BDK's, or else computed from its prevouts when all of
+/// them are in the wallet's tx graph.
+fn tx_fee<'a>(
+    wallet: &Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+    tx: &TransactionDetails,
+) -> Option<u64> {
+    if tx.fee.is_some() {
+        return tx.fee;
+    }
+    graph_fee(wallet, tx.transaction.as_ref()?)
+}
+
+/// Miner fee of `t` in sats, when all of its prevouts are in the wallet's tx graph.
+fn graph_fee<'a>(
+    wallet: &Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+    t: &Transaction,
+) -> Option<u64> {
+    let inputs = t
+        .input
+        .iter()
+        .map(|input| {
+            wallet
+                .tx_graph()
+                .get_txout(input.previous_output)
+                .map(|po| po.value)
+        })
+        .sum::<Option<u64>>()?;
+    let outputs = t.output.iter().map(|output| output.value).sum::<u64>();
+    inputs.checked_sub(outputs)
+}
+
+/// Whether a transaction recorded as confirmed in `block` was reorged out, given the
+/// wallet's `checkpoints`, its `recorded` confirmation time, and the `current` one in
+/// the wallet's canonical history, if it is there. `in_graph` tells whether the
+/// store knows the transaction at all.
+fn is_reorged(
+    checkpoints: &BTreeMap<u32, BlockHash>,
+    block: &BlockId,
+    recorded: Option<&ConfirmationTime>,
+    current: Option<&ConfirmationTime>,
+    in_graph: bool,
+) -> bool {
+    let tip = checkpoints.keys().next_back().copied();
+    let anchor_replaced = match checkpoints.get(&block.height) {
+        Some(hash) => *hash != block.hash,
+        // an invalidated block is removed rather than replaced. A transaction the
+        // store doesn't have is no evidence of a reorg, e.g. after a rescan from a
+        // later height, which leaves out the blocks below it
+        None => in_graph && tip.is_some_and(|tip| block.height <= tip),
+    };
+    let moved = match current {
+        Some(time @ ConfirmationTime::Confirmed { .. }) => Some(time) != recorded,
+        Some(ConfirmationTime::Unconfirmed { .. }) => true,
+        // left out of the canonical history, e.g. after losing a conflict. Only the
+        // anchor decides, since a smaller gap limit also leaves transactions out
+        None => false,
+    };
+    anchor_replaced || moved
+}
+
+/// How a transaction moves the wallet's coins, which decides its bookkeeper events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TxKind {
+    /// We own all the inputs.
+    Spend,
+    /// We own none of the inputs.
+    Receive,
+    /// We own some of the inputs but not others.
+    Shared,
+}
+
+/// Address of `keychain` at `index`. BDK falls back to the external keychain for
+/// wallets without a change descriptor.
+fn address_at<'a>(
+    wallet: &mut Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+    keychain: KeychainKind,
+    index: AddressIndex,
+) -> AddressInfo {
+    match keychain {
+        KeychainKind::External => wallet.get_address(index),
+        KeychainKind::Internal => wallet.get_internal_address(index),
+    }
+}
+
+/// Whether the wallet funded all, none or some of `t`'s inputs.
+fn tx_kind<'a>(
+    wallet: &Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+    t: &Transaction,
+) -> TxKind {
+    let ours = t
+        .input
+        .iter()
+        .filter(|input| {
+            wallet
+                .tx_graph()
+                .get_txout(input.previous_output)
+                .is_some_and(|o| wallet.is_mine(&o.script_pubkey))
+        })
+        .count();
+    if ours == t.input.len() {
+        TxKind::Spend
+    } else if ours == 0 {
+        TxKind::Receive
+    } else {
+        TxKind::Shared
+    }
+}
+
+/// Whether a transaction's net movement adds coins to the wallet or takes them out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    #[serde(alias = "in")]
+    Incoming,
+    #[serde(alias = "out")]
+    Outgoing,
+}
+
+impl FromStr for Direction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "incoming" | "in" => Ok(Direction::Incoming),
+            "outgoing" | "out" => Ok(Direction::Outgoing),
+            _ => Err(anyhow::anyhow!(
+                "unknown direction {s}. Expected one of: [incoming, outgoing]"
+            )),
         }
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, Parser)]
+fn direction(sent: u64, received: u64) -> Direction {
+    if received > sent {
+        Direction::Incoming
+    } else {
+        Direction::Outgoing
+    }
+}
+
+/// What smaug has notified about a transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TxStatus {
+    /// Its bookkeeper movements were sent.
+    Notified,
+    /// It was announced with a `tx_pending` event and awaits confirmation.
+    Pending,
+    /// It lost a conflict and was dropped.
+    Replaced,
+    /// Nothing was sent for it yet.
+    Unnotified,
+}
+
+/// Filters of `listtransactions`. Unconfirmed transactions are left out by height
+/// filters, and transactions without a timestamp by time filters.
+#[derive(Debug, Clone, Default)]
+pub struct TxFilter {
+    pub min_height: Option<u32>,
+    pub max_height: Option<u32>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub direction: Option<Direction>,
+}
+
+impl TxFilter {
+    fn matches(&self, item: &TxListItem) -> bool {
+        let height_ok = match (self.min_height, self.max_height, item.height) {
+            (None, None, _) => true,
+            (min, max, Some(height)) => {
+                min.is_none_or(|min| height >= min) && max.is_none_or(|max| height <= max)
+            }
+            (_, _, None) => false,
+        };
+        let time_ok = match (self.since, self.until, item.timestamp) {
+            (None, None, _) => true,
+            (since, until, Some(timestamp)) => {
+                since.is_none_or(|since| timestamp >= since)
+                    && until.is_none_or(|until| timestamp <= until)
+            }
+            (_, _, None) => false,
+        };
+        let direction_ok = self
+            .direction
+            .is_none_or(|direction| direction == item.direction);
+        height_ok && time_ok && direction_ok
+    }
+}
+
+/// A transaction of a watched wallet, as listed by `listtransactions`.
+#[derive(Debug, Clone, Serialize)]
+pub struct TxListItem {
+    /// Deterministic name of the wallet.
+    pub wallet: String,
+    pub txid: Txid,
+    /// Confirmation height, if confirmed.
+    pub height: Option<u32>,
+    /// Block time if confirmed, or else when smaug first saw it.
+    pub timestamp: Option<u64>,
+    pub sent_sat: u64,
+    pub received_sat: u64,
+    pub fee_sat: Option<u64>,
+    /// `received_sat - sent_sat`.
+    pub net_sat: i64,
+    pub kind: TxKind,
+    pub direction: Direction,
+    pub status: TxStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replaced_by: Option<Txid>,
+}
+
+/// Name of a keychain in command output: `external` or `change`.
+pub fn keychain_name(keychain: KeychainKind) -> &'static str {
+    match keychain {
+        KeychainKind::External => "external",
+        KeychainKind::Internal => "change",
+    }
+}
+
+/// An address of a watched wallet.
+#[derive(Debug, Clone, Serialize)]
+pub struct AddressItem {
+    pub address: String,
+    /// See [`keychain_name`].
+    pub keychain: &'static str,
+    pub index: u32,
+}
+
+impl From<AddressInfo> for AddressItem {
+    fn from(info: AddressInfo) -> Self {
+        AddressItem {
+            address: info.address.to_string(),
+            keychain: keychain_name(info.keychain),
+            index: info.index,
+        }
+    }
+}
+
+/// Where a key of a descriptor comes from.
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyOrigin {
+    pub fingerprint: String,
+    /// Full derivation path from the master key, e.g. `m/84'/0'/0'/0/5`.
+    pub path: String,
+}
+
+/// An address found in a watched wallet by `verifyaddress`.
+#[derive(Debug, Clone, Serialize)]
+pub struct AddressMatch {
+    #[serde(flatten)]
+    pub address: AddressItem,
+    /// Origin of each key the address's script is derived from.
+    pub keys: Vec<KeyOrigin>,
+}
+
+/// Filters of `listutxos`. Depths count the confirming block, so unconfirmed coins
+/// have depth 0.
+#[derive(Debug, Clone, Default)]
+pub struct UtxoFilter {
+    pub min_value: Option<u64>,
+    pub max_value: Option<u64>,
+    pub min_depth: Option<u32>,
+    pub max_depth: Option<u32>,
+}
+
+impl UtxoFilter {
+    fn matches(&self, value: u64, depth: u32) -> bool {
+        self.min_value.is_none_or(|min| value >= min)
+            && self.max_value.is_none_or(|max| value <= max)
+            && self.min_depth.is_none_or(|min| depth >= min)
+            && self.max_depth.is_none_or(|max| depth <= max)
+    }
+}
+
+/// An unspent output of a watched wallet, as listed by `listutxos`.
+#[derive(Debug, Clone, Serialize)]
+pub struct UtxoListItem {
+    /// Deterministic name of the wallet.
+    pub wallet: String,
+    pub outpoint: OutPoint,
+    pub value_sat: u64,
+    /// See [`keychain_name`].
+    pub keychain: &'static str,
+    pub derivation_index: u32,
+    pub address: Option<String>,
+    /// Confirmation height, if confirmed.
+    pub height: Option<u32>,
+    pub depth: u32,
+    pub frozen: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Parser)]
 #[command(author, version, about, long_about = None)]
+#[serde(deny_unknown_fields)]
 pub struct AddArgs {
     /// External descriptor of wallet to add
     pub descriptor: String,
@@ -83,6 +430,117 @@ pub struct AddArgs {
     pub birthday: Option<u32>,
     /// Number of empty addresses to scan before giving up. Must be between 0 and 2147483647
     pub gap: Option<u32>,
+    /// Chain source to sync this wallet with, overriding wd_backend: [esplora, electrum, bitcoind, cln]
+    #[arg(long)]
+    pub backend: Option<BackendKind>,
+    /// Electrum server to sync this wallet with, overriding wd_electrum_url
+    #[arg(long)]
+    pub electrum_url: Option<String>,
+    /// Esplora server to sync this wallet with, overriding wd_esplora_url
+    #[arg(long)]
+    pub esplora_url: Option<String>,
+    /// Watch the public form of descriptors containing private keys instead of refusing them
+    #[arg(long)]
+    #[serde(default)]
+    pub strip_private_keys: bool,
+}
+
+/// Descriptors are shown in public form, so that logs never hold private keys.
+impl fmt::Debug for AddArgs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AddArgs")
+            .field("descriptor", &redact(&self.descriptor))
+            .field(
+                "change_descriptor",
+                &self.change_descriptor.as_deref().map(redact),
+            )
+            .field("birthday", &self.birthday)
+            .field("gap", &self.gap)
+            .field("backend", &self.backend)
+            .field("electrum_url", &self.electrum_url)
+            .field("esplora_url", &self.esplora_url)
+            .field("strip_private_keys", &self.strip_private_keys)
+            .finish()
+    }
+}
+
+/// An unconfirmed transaction of a watched wallet.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PendingTx {
+    pub details: TransactionDetails,
+    /// Unix time smaug first saw the transaction at.
+    pub first_seen: u64,
+}
+
+/// Amounts in sats, split like BDK's [`bdk::wallet::Balance`].
+#[derive(Debug, Default, Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct Balance {
+    /// Confirmed and spendable.
+    pub confirmed_sat: u64,
+    /// Unconfirmed change, which we created ourselves.
+    pub trusted_pending_sat: u64,
+    /// Unconfirmed coins received from others.
+    pub untrusted_pending_sat: u64,
+    /// Coinbase outputs that haven't matured yet.
+    pub immature_sat: u64,
+    pub total_sat: u64,
+}
+
+impl Balance {
+    pub fn add(&mut self, other: &Balance) {
+        self.confirmed_sat += other.confirmed_sat;
+        self.trusted_pending_sat += other.trusted_pending_sat;
+        self.untrusted_pending_sat += other.untrusted_pending_sat;
+        self.immature_sat += other.immature_sat;
+        self.total_sat += other.total_sat;
+    }
+
+    /// Count an unspent output the way BDK does, for a chain whose tip is at `tip`.
+    fn add_coin(&mut self, coin: &Coin, tip: u32) {
+        match coin.height {
+            Some(height) if coin.coinbase && tip + 1 < height + COINBASE_MATURITY => {
+                self.immature_sat += coin.value
+            }
+            Some(_) => self.confirmed_sat += coin.value,
+            None if coin.keychain == KeychainKind::Internal => {
+                self.trusted_pending_sat += coin.value
+            }
+            None => self.untrusted_pending_sat += coin.value,
+        }
+        self.total_sat += coin.value;
+    }
+}
+
+impl From<bdk::wallet::Balance> for Balance {
+    fn from(balance: bdk::wallet::Balance) -> Self {
+        Balance {
+            confirmed_sat: balance.confirmed,
+            trusted_pending_sat: balance.trusted_pending,
+            untrusted_pending_sat: balance.untrusted_pending,
+            immature_sat: balance.immature,
+            total_sat: balance.total(),
+        }
+    }
+}
+
+/// Balance of a watched wallet, with its external and change keychains apart.
+#[derive(Debug, Serialize, Clone)]
+pub struct WalletBalance {
+    #[serde(flatten)]
+    pub balance: Balance,
+    pub external: Balance,
+    pub change: Balance,
+    /// Height the balance was computed at.
+    pub height: u32,
+}
+
+/// An unspent output of the wallet.
+struct Coin {
+    keychain: KeychainKind,
+    value: u64,
+    /// Confirmation height, if confirmed.
+    height: Option<u32>,
+    coinbase: bool,
 }
 
 /// Parameters related to the `smaug` command.
@@ -92,63 +550,175 @@ pub struct DescriptorWallet {
     pub change_descriptor: Option<String>,
     pub birthday: Option<u32>,
     pub gap: Option<u32>,
-    // pub last_synced: Option<BlockTime>,
-    // #[serde(skip_serializing, skip_deserializing)]
+    /// Height of the wallet's chain tip after its last sync.
+    pub last_synced: Option<u32>,
+    /// Stored under their own datastore key, see [`crate::datastore`].
+    #[serde(default)]
     pub transactions: BTreeMap<Txid, TransactionDetails>,
+    /// Block each notified transaction was confirmed in, to detect reorgs.
+    #[serde(default)]
+    pub confirmations: BTreeMap<Txid, BlockId>,
+    /// Unconfirmed transactions announced with a pending notification.
+    #[serde(default)]
+    pub pending: BTreeMap<Txid, PendingTx>,
+    /// Pending transactions dropped while still unconfirmed, mapped to the time the
+    /// backend last saw them then, so that they aren't announced again unless they
+    /// are seen anew.
+    #[serde(default)]
+    pub dropped: BTreeMap<Txid, u64>,
+    /// Transactions that lost a conflict, mapped to the one that replaced them.
+    #[serde(default)]
+    pub replaced: BTreeMap<Txid, Txid>,
+    /// [`EVENTS_VERSION`] the recorded transactions were last notified with.
+    #[serde(default)]
+    pub events_version: u32,
+    /// Coins set aside with `smaug freeze`, e.g. while under audit.
+    #[serde(default)]
+    pub frozen: BTreeSet<OutPoint>,
+    /// Notes attached to coins with `smaug label`.
+    #[serde(default)]
+    pub labels: BTreeMap<OutPoint, String>,
     pub network: Option<Network>,
+    pub backend: Option<BackendKind>,
+    pub electrum_url: Option<String>,
+    pub esplora_url: Option<String>,
 }
 impl DescriptorWallet {
-    fn new(
-        descriptor: &str,
-        change_descriptor: Option<&str>,
-        birthday: Option<u64>,
-        gap: Option<u64>,
-        network: Option<Network>,
-    ) -> Result<Self, WatchError> {
-        let mut params = DescriptorWallet::from_descriptor(descriptor)?;
-        if change_descriptor.is_some() {
-            params = params.with_change_descriptor(change_descriptor.unwrap())?
-        }
-        if birthday.is_some() {
-            params = params.with_birthday(birthday.unwrap())?
-        }
-        if gap.is_some() {
-            params = params.with_gap(gap.unwrap())?
-        }
-        if network.is_some() {
-            params = params.with_network(network.unwrap())?
+    pub fn from_args(args: AddArgs, network: Network) -> Result<Self, SmaugError> {
+        let mut params = Self {
+            network: Some(network),
+            backend: args.backend,
+            electrum_url: args.electrum_url,
+            esplora_url: args.esplora_url,
+            ..DescriptorWallet::from_descriptor(&args.descriptor)?
+        };
+        if let Some(change_descriptor) = &args.change_descriptor {
+            params = params.with_change_descriptor(change_descriptor)?
+        }
+        params = params.with_public_keys(args.strip_private_keys)?;
+        if let Some(birthday) = args.birthday {
+            params = params.with_birthday(birthday as u64)?
         }
+        if let Some(gap) = args.gap {
+            params = params.with_gap(gap as u64)?
+        }
+        params.check_descriptors(network)?;
+        params.fill_defaults();
         Ok(params)
     }
 
-    pub fn from_args(args: AddArgs, network: Network) -> Result<Self, WatchError> {
-        Ok(Self {
-            descriptor: args.descriptor,
-            change_descriptor: args.change_descriptor,
-            birthday: args.birthday,
-            gap: args.gap,
-            transactions: BTreeMap::new(),
-            network: Some(network),
-        })
+    /// Refuse descriptors with private keys, or replace them with their public form
+    /// if `strip`.
+    fn with_public_keys(mut self, strip: bool) -> Result<Self, SmaugError> {
+        let field = if has_private_keys(&self.descriptor) {
+            "descriptor"
+        } else if self
+            .change_descriptor
+            .as_deref()
+            .is_some_and(has_private_keys)
+        {
+            "change_descriptor"
+        } else {
+            return Ok(self);
+        };
+        if !strip {
+            return Err(SmaugError::PrivateKeys { field });
+        }
+        self.strip_private_keys();
+        Ok(self)
     }
 
-    fn from_descriptor(descriptor: &str) -> Result<Self, WatchError> {
+    /// Replace descriptors that have private keys with their public form, which
+    /// keeps the wallet's name. Returns whether any was replaced.
+    pub fn strip_private_keys(&mut self) -> bool {
+        let mut stripped = false;
+        let descriptors = iter::once(&mut self.descriptor).chain(self.change_descriptor.as_mut());
+        for descriptor in descriptors {
+            if let Some((public, true)) = public_descriptor(descriptor) {
+                *descriptor = public;
+                stripped = true;
+            }
+        }
+        stripped
+    }
+
+    /// Parse both descriptors the way BDK will when opening the wallet.
+    fn check_descriptors(&self, network: Network) -> Result<(), SmaugError> {
+        let secp = Secp256k1::<All>::new();
+        let descriptors = [
+            ("descriptor", Some(&self.descriptor)),
+            ("change_descriptor", self.change_descriptor.as_ref()),
+        ];
+        for (field, descriptor) in descriptors {
+            if let Some(descriptor) = descriptor {
+                if let Some(key_network) = key_network(descriptor) {
+                    // tpubs are shared by all the test networks
+                    if (key_network == Network::Bitcoin) != (network == Network::Bitcoin) {
+                        return Err(SmaugError::NetworkMismatch {
+                            expected: network,
+                            found: key_network,
+                            source: field.to_owned(),
+                        });
+                    }
+                }
+                descriptor
+                    .as_str()
+                    .into_wallet_descriptor(&secp, network)
+                    .map_err(|e| SmaugError::InvalidDescriptor {
+                        field,
+                        reason: e.to_string(),
+                    })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Store the birthday and gap that scanning actually uses when none were given,
+    /// so they are persisted and listed.
+    pub fn fill_defaults(&mut self) {
+        self.birthday.get_or_insert(0);
+        self.gap.get_or_insert(STOP_GAP as u32);
+    }
+
+    /// Number of consecutive unused scripts to scan per keychain before giving up.
+    pub fn stop_gap(&self) -> usize {
+        self.gap.map_or(STOP_GAP, |gap| gap as usize)
+    }
+
+    fn from_descriptor(descriptor: &str) -> Result<Self, SmaugError> {
+        if descriptor.is_empty() {
+            return Err(SmaugError::InvalidDescriptor {
+                field: "descriptor",
+                reason: "descriptor is empty".to_owned(),
+            });
+        }
         Ok(Self {
             descriptor: descriptor.to_owned(),
             change_descriptor: None,
             birthday: None,
             gap: None,
-            // last_synced: None,
+            last_synced: None,
             transactions: BTreeMap::new(),
+            confirmations: BTreeMap::new(),
+            pending: BTreeMap::new(),
+            dropped: BTreeMap::new(),
+            replaced: BTreeMap::new(),
+            events_version: EVENTS_VERSION,
+            frozen: BTreeSet::new(),
+            labels: BTreeMap::new(),
             network: None,
+            backend: None,
+            electrum_url: None,
+            esplora_url: None,
         })
     }
 
-    fn with_change_descriptor(self, change_descriptor: &str) -> Result<Self, WatchError> {
+    fn with_change_descriptor(self, change_descriptor: &str) -> Result<Self, SmaugError> {
         if change_descriptor.is_empty() {
-            Err(WatchError::InvalidChangeDescriptor(
-                "change_descriptor is empty".to_owned(),
-            ))
+            Err(SmaugError::InvalidDescriptor {
+                field: "change_descriptor",
+                reason: "change_descriptor is empty".to_owned(),
+            })
         } else {
             Ok(Self {
                 change_descriptor: Some(String::from(change_descriptor)),
@@ -157,11 +727,9 @@ impl DescriptorWallet {
         }
     }
 
-    fn with_birthday(self, birthday: u64) -> Result<Self, WatchError> {
+    fn with_birthday(self, birthday: u64) -> Result<Self, SmaugError> {
         if birthday > u32::MAX as u64 {
-            Err(WatchError::InvalidBirthday(format!(
-                "birthday must be between 0 and 4294967295. Received: {birthday}"
-            )))
+            Err(SmaugError::InvalidBirthday(birthday))
         } else {
             Ok(Self {
                 birthday: Some(birthday as u32),
@@ -170,11 +738,9 @@ impl DescriptorWallet {
         }
     }
 
-    fn with_gap(self, gap: u64) -> Result<Self, WatchError> {
+    pub fn with_gap(self, gap: u64) -> Result<Self, SmaugError> {
         if gap > u32::MAX as u64 / 2 {
-            Err(WatchError::InvalidBirthday(format!(
-                "gap must be between 0 and 2147483647. Received: {gap}"
-            )))
+            Err(SmaugError::InvalidGap(gap))
         } else {
             Ok(Self {
                 gap: Some(gap as u32),
@@ -183,54 +749,989 @@ impl DescriptorWallet {
         }
     }
 
-    fn with_network(self, network: Network) -> Result<Self, WatchError> {
-        Ok(Self {
-            network: Some(network),
-            ..self
-        })
+    pub fn update_last_synced(&mut self, last_synced: u32) {
+        self.last_synced = Some(last_synced);
     }
 
-    // pub fn update_last_synced(&mut self, last_synced: BlockTime) {
-    //     self.last_synced = Some(last_synced);
-    // }
-
-    pub fn update_transactions(
-        &mut self,
+    /// Confirmed transactions that weren't recorded yet. Unconfirmed transactions
+    /// are left out until they confirm.
+    pub fn new_transactions(
+        &self,
         transactions: Vec<TransactionDetails>,
     ) -> Vec<TransactionDetails> {
-        let mut new_txs = vec![];
-        for tx in transactions {
-            if !self.transactions.contains_key(&tx.txid) {
-                new_txs.push(tx.clone());
-                self.transactions.insert(tx.txid, tx);
-            }
-        }
-        new_txs
-        // self.transactions = transactions;
+        transactions
+            .into_iter()
+            .filter(|tx| match tx.confirmation_time {
+                ConfirmationTime::Confirmed { .. } => !self.transactions.contains_key(&tx.txid),
+                ConfirmationTime::Unconfirmed { .. } => false,
+            })
+            .collect()
+    }
+
+    /// The wallet's network, which every wallet is given when it is added.
+    pub fn network(&self) -> Result<Network, SmaugError> {
+        self.network
+            .ok_or_else(|| SmaugError::Internal("wallet has no network".to_owned()))
     }
 
     pub fn get_name(&self) -> Result<String, Error> {
         Ok(wallet_name_from_descriptor(
             &self.descriptor,
             self.change_descriptor.as_ref(),
-            self.network.unwrap(),
+            self.network()?,
             &Secp256k1::<All>::new(),
         )?)
     }
 
+    /// Path of the wallet's BDK store.
+    fn db_path(&self) -> Result<PathBuf, Error> {
+        Ok(home_dir()
+            .ok_or_else(|| SmaugError::Internal("cannot find home directory".to_owned()))?
+            .join(DATADIR)
+            .join(format!("{}.db", self.get_name()?)))
+    }
+
+    /// Delete the wallet's BDK store so that the next sync rediscovers its history
+    /// from scratch. Recorded transactions are kept, so they aren't notified twice.
+    pub fn reset_store(&mut self) -> Result<(), Error> {
+        let db_path = self.db_path()?;
+        match fs::remove_file(&db_path) {
+            Ok(()) => log::info!("removed wallet store {:?}", db_path),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        self.last_synced = None;
+        Ok(())
+    }
+
+    /// Sync with `backend` and send notifications for transactions that weren't
+    /// recorded yet. A transaction is only recorded once all of its notifications
+    /// were queued for lightningd, so that the recorded set, once persisted, never
+    /// claims one that wasn't sent. lightningd doesn't acknowledge notifications, so
+    /// one lost after that isn't noticed. The first one that fails to queue is
+    /// returned, and its transaction and the ones after it are left for the next
+    /// sync, which sends all of that transaction's notifications again, including
+    /// the ones that went out.
+    pub async fn sync_and_notify(
+        &mut self,
+        plugin: &Plugin<State>,
+        backend: &Backend,
+        mode: SyncMode,
+    ) -> Result<(), Error> {
+        let wallet = self.fetch_wallet(backend, mode).await?;
+        self.retract_reorged(plugin, &wallet).await?;
+        let bdk_transactions_iter = wallet.transactions();
+        let mut transactions = Vec::<TransactionDetails>::new();
+        for bdk_transaction in bdk_transactions_iter {
+            log::info!("BDK transaction = {:?}", bdk_transaction.node.tx);
+            if let Some(tx) = wallet.get_tx(bdk_transaction.node.txid, true) {
+                transactions.push(tx);
+            }
+        }
+
+        self.detect_replacements(plugin, &wallet, &transactions)
+            .await?;
+        self.prune_spent(&wallet);
+        self.update_pending(plugin, &wallet, &transactions).await?;
+        if transactions.len() > 0 {
+            log::info!("found some transactions: {:?}", transactions);
+            let new_txs = self.new_transactions(transactions);
+            if new_txs.len() > 0 {
+                for tx in new_txs {
+                    log::info!("new tx found!: {:?}", tx);
+                    self.send_notifications_for_tx(plugin, &wallet, tx.clone())
+                        .await?;
+                    self.record_confirmation(&wallet, &tx);
+                    self.transactions.insert(tx.txid, tx);
+                }
+            } else {
+                log::info!("no new txs this time");
+            }
+        } else {
+            log::info!("found no transactions");
+        }
+        Ok(())
+    }
+
+    /// Find the transactions in BDK's tx graph that spend the same outputs as one of
+    /// the wallet's canonical `transactions`, and mark them as replaced by it.
+    ///
+    /// BDK leaves losers out of the canonical history, so they never get bookkeeper
+    /// movements. A loser that was already notified, e.g. a confirmed transaction
+    /// double spent by a reorg, is retracted with a [`TX_REORGED_TAG`] notification.
+    async fn detect_replacements<'a>(
+        &mut self,
+        plugin: &Plugin<State>,
+        wallet: &Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+        transactions: &[TransactionDetails],
+    ) -> Result<(), Error> {
+        let canonical = transactions
+            .iter()
+            .map(|tx| tx.txid)
+            .collect::<HashSet<_>>();
+        let mut losers = Vec::new();
+        for tx in transactions {
+            let t = match &tx.transaction {
+                Some(t) => t,
+                None => continue,
+            };
+            for (_, loser) in wallet.tx_graph().direct_conflicts_of_tx(t) {
+                if loser == tx.txid || canonical.contains(&loser) {
+                    continue;
+                }
+                if self.replaced.insert(loser, tx.txid).is_none() {
+                    log::info!("tx {} was replaced by {}", loser, tx.txid);
+                }
+                losers.push(loser);
+            }
+        }
+
+        for loser in losers {
+            let recorded_height =
+                self.transactions
+                    .get(&loser)
+                    .and_then(|tx| match tx.confirmation_time {
+                        ConfirmationTime::Confirmed { height, .. } => Some(height),
+                        ConfirmationTime::Unconfirmed { .. } => None,
+                    });
+            match self.confirmations.get(&loser) {
+                Some(&block) => {
+                    self.retract(plugin, loser, block.height, Some(block.hash))
+                        .await?
+                }
+                None => match recorded_height {
+                    Some(height) => self.retract(plugin, loser, height, None).await?,
+                    // never notified, so there is nothing to retract
+                    None => {
+                        self.transactions.remove(&loser);
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Announce newly seen unconfirmed transactions, and drop pending ones that
+    /// confirmed, were replaced, or were left unconfirmed past the mempool expiry.
+    async fn update_pending<'a>(
+        &mut self,
+        plugin: &Plugin<State>,
+        wallet: &Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+        transactions: &[TransactionDetails],
+    ) -> Result<(), Error> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let account = format!("smaug:{}", self.get_name()?);
+        let coin_type = coin_type(self.network()?);
+
+        let mut canonical = BTreeMap::new();
+        for tx in transactions {
+            canonical.insert(tx.txid, tx);
+            let last_seen = match tx.confirmation_time {
+                ConfirmationTime::Confirmed { .. } => {
+                    // promoted: the confirmed events are sent as for any other transaction
+                    self.pending.remove(&tx.txid);
+                    self.dropped.remove(&tx.txid);
+                    continue;
+                }
+                ConfirmationTime::Unconfirmed { last_seen } => last_seen,
+            };
+            if self.pending.contains_key(&tx.txid) || self.transactions.contains_key(&tx.txid) {
+                continue;
+            }
+            match self.dropped.get(&tx.txid) {
+                // still the copy that was dropped, e.g. one BDK keeps after eviction
+                Some(&dropped_seen) if dropped_seen == last_seen => continue,
+                Some(_) => {
+                    log::info!("dropped tx {} was seen again", tx.txid);
+                    self.dropped.remove(&tx.txid);
+                }
+                None => {}
+            }
+            log::info!("new pending tx found!: {:?}", tx);
+            let tx_pending = TxPending {
+                account: account.clone(),
+                txid: tx.txid,
+                received_msat: Msat::from_sat(tx.received),
+                sent_msat: Msat::from_sat(tx.sent),
+                fee_msat: tx_fee(wallet, tx).map(Msat::from_sat),
+                coin_type,
+                timestamp: now,
+            };
+            notify(plugin, TX_PENDING_TAG, tx_pending).await?;
+            self.pending.insert(
+                tx.txid,
+                PendingTx {
+                    details: tx.clone(),
+                    first_seen: now,
+                },
+            );
+        }
+
+        let dropped = self
+            .pending
+            .iter()
+            .filter_map(|(txid, pending)| {
+                if !canonical.contains_key(txid) {
+                    // BDK only leaves out unconfirmed transactions that lost a conflict
+                    Some((*txid, DropReason::Replaced))
+                } else if now.saturating_sub(pending.first_seen) > MEMPOOL_EXPIRY_SECS {
+                    Some((*txid, DropReason::Evicted))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        for (txid, reason) in dropped {
+            log::info!("pending tx {} dropped: {:?}", txid, reason);
+            let tx_pending_dropped = TxPendingDropped {
+                account: account.clone(),
+                txid,
+                reason,
+                replaced_by: self.replaced.get(&txid).copied(),
+                coin_type,
+            };
+            notify(plugin, TX_PENDING_DROPPED_TAG, tx_pending_dropped).await?;
+            self.pending.remove(&txid);
+            if let Some(tx) = canonical.get(&txid) {
+                if let ConfirmationTime::Unconfirmed { last_seen } = tx.confirmation_time {
+                    self.dropped.insert(txid, last_seen);
+                }
+            }
+        }
+        // transactions that left BDK's history, e.g. replaced ones, can be forgotten
+        self.dropped.retain(|txid, _| canonical.contains_key(txid));
+        Ok(())
+    }
+
+    /// Whether the recorded transactions were notified with outdated event payloads.
+    pub fn needs_reemit(&self) -> bool {
+        self.events_version < EVENTS_VERSION
+    }
+
+    /// Send the notifications of every recorded transaction again, in confirmation
+    /// order, without syncing. Returns the number of transactions replayed.
+    pub async fn reemit(&mut self, plugin: &Plugin<State>) -> Result<usize, Error> {
+        let wallet = self.open_wallet()?;
+        let mut transactions = self.transactions.values().cloned().collect::<Vec<_>>();
+        transactions.sort_by_key(|tx| match tx.confirmation_time {
+            ConfirmationTime::Confirmed { height, .. } => height,
+            ConfirmationTime::Unconfirmed { .. } => u32::MAX,
+        });
+        let count = transactions.len();
+        for tx in transactions {
+            log::info!("re-emitting notifications for tx {}", tx.txid);
+            self.send_notifications_for_tx(plugin, &wallet, tx).await?;
+        }
+        self.events_version = EVENTS_VERSION;
+        Ok(count)
+    }
+
+    /// The wallet's balance as of its last sync, or, with `height`, counting only
+    /// the transactions confirmed at or below it.
+    pub fn balance(&self, height: Option<u32>) -> Result<WalletBalance, Error> {
+        let wallet = self.open_wallet()?;
+        let tip = wallet
+            .checkpoints()
+            .keys()
+            .next_back()
+            .copied()
+            .unwrap_or(0);
+        let (coins, tip) = match height {
+            Some(height) => (self.coins_at(&wallet, height), height.min(tip)),
+            None => (self.coins(&wallet), tip),
+        };
+
+        let mut external = Balance::default();
+        let mut change = Balance::default();
+        for coin in &coins {
+            match coin.keychain {
+                KeychainKind::External => external.add_coin(coin, tip),
+                KeychainKind::Internal => change.add_coin(coin, tip),
+            }
+        }
+        let balance = match height {
+            Some(_) => {
+                let mut balance = external;
+                balance.add(&change);
+                balance
+            }
+            None => wallet.get_balance().into(),
+        };
+        Ok(WalletBalance {
+            balance,
+            external,
+            change,
+            height: tip,
+        })
+    }
+
+    /// The wallet's current unspent outputs.
+    fn coins<'a>(
+        &self,
+        wallet: &Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+    ) -> Vec<Coin> {
+        wallet
+            .list_unspent()
+            .map(|utxo| Coin {
+                keychain: utxo.keychain,
+                value: utxo.txout.value,
+                height: match utxo.confirmation_time {
+                    ConfirmationTime::Confirmed { height, .. } => Some(height),
+                    ConfirmationTime::Unconfirmed { .. } => None,
+                },
+                coinbase: wallet
+                    .tx_graph()
+                    .get_tx(utxo.outpoint.txid)
+                    .is_some_and(|t| t.is_coin_base()),
+            })
+            .collect()
+    }
+
+    /// The wallet's unspent outputs as of its last sync that match `filter`, in
+    /// outpoint order.
+    pub fn list_utxos(&self, filter: &UtxoFilter) -> Result<Vec<UtxoListItem>, Error> {
+        let wallet = self.open_wallet()?;
+        let name = self.get_name()?;
+        let tip = wallet
+            .checkpoints()
+            .keys()
+            .next_back()
+            .copied()
+            .unwrap_or(0);
+        let mut items = Vec::new();
+        for utxo in wallet.list_unspent() {
+            let height = match utxo.confirmation_time {
+                ConfirmationTime::Confirmed { height, .. } => Some(height),
+                ConfirmationTime::Unconfirmed { .. } => None,
+            };
+            let depth = height.map_or(0, |height| (tip + 1).saturating_sub(height));
+            if !filter.matches(utxo.txout.value, depth) {
+                continue;
+            }
+            items.push(UtxoListItem {
+                wallet: name.clone(),
+                outpoint: utxo.outpoint,
+                value_sat: utxo.txout.value,
+                keychain: keychain_name(utxo.keychain),
+                derivation_index: utxo.derivation_index,
+                address: self.network.and_then(|network| {
+                    Address::from_script(&utxo.txout.script_pubkey, network)
+                        .ok()
+                        .map(|address| address.to_string())
+                }),
+                height,
+                depth,
+                frozen: self.frozen.contains(&utxo.outpoint),
+                label: self.labels.get(&utxo.outpoint).cloned(),
+            });
+        }
+        items.sort_by_key(|item| item.outpoint);
+        Ok(items)
+    }
+
+    /// Reveal the next address of `keychain`, or every address up to `index`, and
+    /// store them so they are included in later syncs. `index` can be at most the
+    /// gap limit past the last revealed address, and only for descriptors with a
+    /// wildcard.
+    pub fn new_address(
+        &self,
+        keychain: KeychainKind,
+        index: Option<u32>,
+    ) -> Result<AddressItem, Error> {
+        let mut wallet = self.open_wallet()?;
+        // like BDK, wallets without a change descriptor use the external one
+        let keychain = match self.change_descriptor {
+            Some(_) => keychain,
+            None => KeychainKind::External,
+        };
+        let last_revealed = wallet.spk_index().last_revealed_index(&keychain);
+        let info = match index {
+            Some(index) if last_revealed.is_some_and(|last| index <= last) => {
+                address_at(&mut wallet, keychain, AddressIndex::Peek(index))
+            }
+            Some(index) => {
+                if !wallet.get_descriptor_for_keychain(keychain).has_wildcard() {
+                    return Err(SmaugError::InvalidParams {
+                        message: "index can't be given for a descriptor without a wildcard"
+                            .to_owned(),
+                        usage: None,
+                    }
+                    .into());
+                }
+                // the last index `verify_address` searches
+                let max = (last_revealed.map_or(0, |last| last + 1) + self.stop_gap() as u32)
+                    .saturating_sub(1);
+                if index > max {
+                    return Err(SmaugError::InvalidParams {
+                        message: format!(
+                            "index must be at most {max}, the gap limit past the last revealed address"
+                        ),
+                        usage: None,
+                    }
+                    .into());
+                }
+                // an update without chain changes, which reveals up to `index` in one go
+                let mut update = LocalUpdate::new(LocalChain::from(wallet.checkpoints().clone()));
+                update.last_active_indices.insert(keychain, index);
+                wallet
+                    .apply_update(update)
+                    .map_err(|e| self.store_error(e))?;
+                address_at(&mut wallet, keychain, AddressIndex::Peek(index))
+            }
+            None => address_at(&mut wallet, keychain, AddressIndex::New),
+        };
+        wallet.commit().map_err(|e| self.store_error(e))?;
+        Ok(info.into())
+    }
+
+    /// Derive the address of `keychain` at `index` without revealing it.
+    pub fn peek_address(&self, keychain: KeychainKind, index: u32) -> Result<AddressItem, Error> {
+        // hardened indexes start there, and can't be derived from public keys
+        if index >= 1 << 31 {
+            return Err(SmaugError::InvalidParams {
+                message: format!("index must be below 2147483648. Received: {index}"),
+                usage: None,
+            }
+            .into());
+        }
+        let mut wallet = self.open_wallet()?;
+        Ok(address_at(&mut wallet, keychain, AddressIndex::Peek(index)).into())
+    }
+
+    /// Look for `address` in both keychains, up to the gap limit past the last
+    /// revealed index of each.
+    pub fn verify_address(&self, address: &Address) -> Result<Option<AddressMatch>, Error> {
+        if let Some(network) = self.network {
+            if !address.is_valid_for_network(network) {
+                return Ok(None);
+            }
+        }
+        let mut wallet = self.open_wallet()?;
+        let script = address.script_pubkey();
+        for keychain in [KeychainKind::External, KeychainKind::Internal] {
+            let end = wallet
+                .spk_index()
+                .last_revealed_index(&keychain)
+                .map_or(0, |last| last + 1)
+                + self.stop_gap() as u32;
+            for index in 0..end {
+                let info = address_at(&mut wallet, keychain, AddressIndex::Peek(index));
+                // without a change descriptor, both keychains are the external one
+                if info.keychain != keychain || info.address.script_pubkey() != script {
+                    continue;
+                }
+                let mut keys = Vec::new();
+                wallet
+                    .get_descriptor_for_keychain(keychain)
+                    .at_derivation_index(index)
+                    .for_each_key(|key| {
+                        keys.push(KeyOrigin {
+                            fingerprint: key.master_fingerprint().to_string(),
+                            path: key.full_derivation_path().to_string(),
+                        });
+                        true
+                    });
+                return Ok(Some(AddressMatch {
+                    address: info.into(),
+                    keys,
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Freeze or unfreeze one of the wallet's unspent coins. A coin that was spent
+    /// since it was frozen can still be unfrozen.
+    pub fn set_frozen(&mut self, outpoint: OutPoint, frozen: bool) -> Result<(), Error> {
+        if frozen || !self.frozen.contains(&outpoint) {
+            self.check_unspent(outpoint)?;
+        }
+        if frozen {
+            self.frozen.insert(outpoint);
+        } else {
+            self.frozen.remove(&outpoint);
+        }
+        Ok(())
+    }
+
+    /// Attach `label` to one of the wallet's unspent coins, or remove its label. The
+    /// label of a coin that was spent since can still be removed.
+    pub fn set_label(&mut self, outpoint: OutPoint, label: Option<String>) -> Result<(), Error> {
+        if label.is_some() || !self.labels.contains_key(&outpoint) {
+            self.check_unspent(outpoint)?;
+        }
+        match label {
+            Some(label) => self.labels.insert(outpoint, label),
+            None => self.labels.remove(&outpoint),
+        };
+        Ok(())
+    }
+
+    /// Fail unless `outpoint` is one of the wallet's unspent outputs as of its last
+    /// sync.
+    fn check_unspent(&self, outpoint: OutPoint) -> Result<(), Error> {
+        let wallet = self.open_wallet()?;
+        if wallet.list_unspent().any(|utxo| utxo.outpoint == outpoint) {
+            Ok(())
+        } else {
+            Err(SmaugError::UnknownCoin {
+                wallet: self.get_name()?,
+                outpoint,
+            }
+            .into())
+        }
+    }
+
+    /// Forget the frozen state and labels of coins that were spent. Outputs of
+    /// transactions the store doesn't have are kept, since their coins may only be
+    /// missing until a rescan finds them again.
+    fn prune_spent<'a>(
+        &mut self,
+        wallet: &Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+    ) {
+        let unspent = wallet
+            .list_unspent()
+            .map(|utxo| utxo.outpoint)
+            .collect::<HashSet<_>>();
+        let spent = |outpoint: &OutPoint| {
+            !unspent.contains(outpoint) && wallet.get_tx(outpoint.txid, false).is_some()
+        };
+        self.frozen.retain(|outpoint| !spent(outpoint));
+        self.labels.retain(|outpoint, _| !spent(outpoint));
+    }
+
+    /// The wallet's outputs that were unspent at `height`, going by the
+    /// transactions of its best chain confirmed at or below it.
+    fn coins_at<'a>(
+        &self,
+        wallet: &Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+        height: u32,
+    ) -> Vec<Coin> {
+        let mut outputs = BTreeMap::<OutPoint, Coin>::new();
+        let mut spent = HashSet::<OutPoint>::new();
+        for canonical_tx in wallet.transactions() {
+            let t = canonical_tx.node.tx;
+            let tx_height = match wallet.get_tx(canonical_tx.node.txid, false) {
+                Some(TransactionDetails {
+                    confirmation_time: ConfirmationTime::Confirmed { height, .. },
+                    ..
+                }) => height,
+                _ => continue,
+            };
+            if tx_height > height {
+                continue;
+            }
+            spent.extend(t.input.iter().map(|input| input.previous_output));
+            for (vout, txout) in t.output.iter().enumerate() {
+                if let Some(&(keychain, _)) = wallet.spk_index().index_of_spk(&txout.script_pubkey)
+                {
+                    outputs.insert(
+                        OutPoint::new(canonical_tx.node.txid, vout as u32),
+                        Coin {
+                            keychain,
+                            value: txout.value,
+                            height: Some(tx_height),
+                            coinbase: t.is_coin_base(),
+                        },
+                    );
+                }
+            }
+        }
+        outputs
+            .into_iter()
+            .filter(|(outpoint, _)| !spent.contains(outpoint))
+            .map(|(_, coin)| coin)
+            .collect()
+    }
+
+    /// The wallet's transactions as of its last sync that match `filter`, including
+    /// the ones that were replaced, in no particular order.
+    pub fn list_transactions(&self, filter: &TxFilter) -> Result<Vec<TxListItem>, Error> {
+        let wallet = self.open_wallet()?;
+        let name = self.get_name()?;
+        let mut items = Vec::new();
+        for canonical_tx in wallet.transactions() {
+            let tx = match wallet.get_tx(canonical_tx.node.txid, true) {
+                Some(tx) => tx,
+                None => continue,
+            };
+            let (height, timestamp) = match tx.confirmation_time {
+                ConfirmationTime::Confirmed { height, time } => (Some(height), Some(time)),
+                ConfirmationTime::Unconfirmed { .. } => (
+                    None,
+                    self.pending.get(&tx.txid).map(|pending| pending.first_seen),
+                ),
+            };
+            let status = if self.transactions.contains_key(&tx.txid) {
+                TxStatus::Notified
+            } else if self.pending.contains_key(&tx.txid) {
+                TxStatus::Pending
+            } else {
+                TxStatus::Unnotified
+            };
+            items.push(TxListItem {
+                wallet: name.clone(),
+                txid: tx.txid,
+                height,
+                timestamp,
+                sent_sat: tx.sent,
+                received_sat: tx.received,
+                fee_sat: tx_fee(&wallet, &tx),
+                net_sat: tx.received as i64 - tx.sent as i64,
+                kind: tx_kind(&wallet, canonical_tx.node.tx),
+                direction: direction(tx.sent, tx.received),
+                status,
+                replaced_by: None,
+            });
+        }
+        for (txid, replaced_by) in &self.replaced {
+            let t = match wallet.tx_graph().get_tx(*txid) {
+                Some(t) => t,
+                None => continue,
+            };
+            let (sent, received) = wallet.spk_index().sent_and_received(t);
+            items.push(TxListItem {
+                wallet: name.clone(),
+                txid: *txid,
+                height: None,
+                timestamp: None,
+                sent_sat: sent,
+                received_sat: received,
+                fee_sat: graph_fee(&wallet, t),
+                net_sat: received as i64 - sent as i64,
+                kind: tx_kind(&wallet, t),
+                direction: direction(sent, received),
+                status: TxStatus::Replaced,
+                replaced_by: Some(*replaced_by),
+            });
+        }
+        items.retain(|item| filter.matches(item));
+        Ok(items)
+    }
+
+    /// Remember the block `tx` is confirmed in: the wallet's checkpoint at its
+    /// confirmation height, or else the first one above, which BDK anchors it to.
+    fn record_confirmation<'a>(
+        &mut self,
+        wallet: &Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+        tx: &TransactionDetails,
+    ) {
+        if let ConfirmationTime::Confirmed { height, .. } = tx.confirmation_time {
+            if let Some((&height, &hash)) = wallet.checkpoints().range(height..).next() {
+                self.confirmations.insert(tx.txid, BlockId { height, hash });
+            }
+        }
+    }
+
+    /// Emit a [`TX_REORGED_TAG`] notification for each recorded transaction whose
+    /// confirming block was replaced in the wallet's best chain, or that the store
+    /// now has unconfirmed or at another height, then forget it, so that it is
+    /// notified again with its new height once it reconfirms. Transactions the store
+    /// doesn't have are left alone.
+    async fn retract_reorged<'a>(
+        &mut self,
+        plugin: &Plugin<State>,
+        wallet: &Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+    ) -> Result<(), Error> {
+        // transactions recorded before confirmations were tracked, and that the store
+        // knows, so that the checkpoint found is the one BDK anchors them to
+        let untracked = self
+            .transactions
+            .values()
+            .filter(|tx| !self.confirmations.contains_key(&tx.txid))
+            .filter(|tx| wallet.get_tx(tx.txid, false).is_some())
+            .cloned()
+            .collect::<Vec<_>>();
+        for tx in untracked {
+            self.record_confirmation(wallet, &tx);
+        }
+
+        let reorged = self
+            .confirmations
+            .iter()
+            .filter(|(txid, block)| {
+                let recorded = self.transactions.get(*txid).map(|tx| &tx.confirmation_time);
+                let current = wallet.get_tx(**txid, false).map(|tx| tx.confirmation_time);
+                let in_graph = wallet.tx_graph().get_tx(**txid).is_some();
+                is_reorged(
+                    wallet.checkpoints(),
+                    block,
+                    recorded,
+                    current.as_ref(),
+                    in_graph,
+                )
+            })
+            .map(|(txid, block)| (*txid, *block))
+            .collect::<Vec<_>>();
+
+        for (txid, block) in reorged {
+            log::info!("tx {} was reorged out of block {:?}", txid, block);
+            self.retract(plugin, txid, block.height, Some(block.hash))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Send a [`TX_REORGED_TAG`] notification for a recorded transaction confirmed
+    /// at `height`, in the block `hash` when it is known, then forget it.
+    async fn retract(
+        &mut self,
+        plugin: &Plugin<State>,
+        txid: Txid,
+        height: u32,
+        hash: Option<BlockHash>,
+    ) -> Result<(), Error> {
+        let tx_reorged = TxReorged {
+            account: format!("smaug:{}", self.get_name()?),
+            txid,
+            blockheight: height,
+            blockhash: hash.map(|hash| hash.to_string()),
+        };
+        notify(plugin, TX_REORGED_TAG, tx_reorged).await?;
+        self.transactions.remove(&txid);
+        self.confirmations.remove(&txid);
+        Ok(())
+    }
+
+    /// Open the wallet's BDK store and sync it with `backend`.
+    ///
+    /// A wallet that was never synced always gets a full scan.
     pub async fn fetch_wallet<'a>(
+        &mut self,
+        backend: &Backend,
+        mode: SyncMode,
+    ) -> Result<Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>, Error>
+    {
+        let mut wallet = self.open_wallet()?;
+
+        let balance = wallet.get_balance();
+        log::info!("Wallet balance before syncing: {} sats", balance.total());
+
+        log::info!("Syncing...");
+        let mode = match self.last_synced {
+            Some(_) => mode,
+            None => SyncMode::Full,
+        };
+        log::info!("sync mode: {:?}", mode);
+        self.sync_with(&mut wallet, backend, mode)
+            .await
+            .map_err(|e| SmaugError::Backend {
+                backend: backend.kind(),
+                message: format!("{:#}", e),
+            })?;
+        self.fetch_prevouts(&mut wallet, backend).await;
+        wallet.commit().map_err(|e| self.store_error(e))?;
+        if let Some((&height, _)) = wallet.checkpoints().iter().next_back() {
+            self.update_last_synced(height);
+        }
+
+        let balance = wallet.get_balance();
+        log::info!("Wallet balance after syncing: {} sats", balance.total());
+        return Ok(wallet);
+    }
+
+    /// Apply what `backend` finds to the wallet, without committing it.
+    async fn sync_with<'a>(
+        &self,
+        wallet: &mut Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+        backend: &Backend,
+        mode: SyncMode,
+    ) -> Result<(), Error> {
+        let network = json!(self.network);
+        log::info!("using network: {}", network);
+        // esplora and electrum look up scripts directly, so only the block-walking
+        // backends can skip history before the birthday
+        let stop_gap = self.stop_gap();
+        log::info!("using gap: {}, birthday: {:?}", stop_gap, self.birthday);
+        match backend {
+            Backend::Esplora { url } => {
+                let url = self.esplora_url(url)?;
+                log::info!("using esplora url: {}", url);
+                let client =
+                    // esplora_client::Builder::new("https://blockstream.info/testnet/api").build_async()?;
+                    esplora_client::Builder::new(url.as_str()).build_async()?;
+
+                let local_chain = wallet.checkpoints();
+                let update = match mode {
+                    SyncMode::Full => {
+                        let keychain_spks = wallet
+                            .spks_of_all_keychains()
+                            .into_iter()
+                            .map(|(k, k_spks)| {
+                                let mut once = Some(());
+                                let k_spks = k_spks.inspect(move |(spk_i, _)| match once.take() {
+                                    Some(_) => log::info!("\nScanning keychain [{:?}]", k),
+                                    None => log::info!(" {:<3}", spk_i),
+                                });
+                                (k, k_spks)
+                            })
+                            .collect();
+                        log::info!("CAG finished scanning");
+                        client
+                            .scan(
+                                local_chain,
+                                keychain_spks,
+                                [],
+                                [],
+                                stop_gap,
+                                PARALLEL_REQUESTS,
+                            )
+                            .await?
+                    }
+                    SyncMode::Incremental => {
+                        let keychain_spks = incremental_spks(wallet, SYNC_LOOKAHEAD);
+                        let spk_count = keychain_spks.values().map(Vec::len).sum::<usize>();
+                        client
+                            .scan(
+                                local_chain,
+                                keychain_spks,
+                                unconfirmed_txids(wallet),
+                                [],
+                                // the scripts are already bounded, query all of them
+                                spk_count,
+                                PARALLEL_REQUESTS,
+                            )
+                            .await?
+                    }
+                };
+                wallet.apply_update(update)?;
+            }
+            Backend::Electrum { url } => {
+                log::info!("using electrum url: {}", url);
+                tokio::task::block_in_place(|| {
+                    sync_electrum(wallet, url, mode, stop_gap, PARALLEL_REQUESTS)
+                })?;
+            }
+            Backend::Bitcoind(config) => {
+                log::info!("using bitcoind rpc url: {}", config.url);
+                tokio::task::block_in_place(|| {
+                    sync_bitcoind(wallet, config, self.birthday, stop_gap)
+                })?;
+            }
+            Backend::Cln { rpc_file } => {
+                log::info!("using lightningd's bitcoin backend");
+                sync_cln(wallet, rpc_file, self.birthday, stop_gap).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// `url`, or the default Esplora server of the wallet's network.
+    fn esplora_url(&self, url: &Option<String>) -> Result<String, Error> {
+        match url {
+            Some(url) => Ok(url.clone()),
+            None => get_network_url(json!(self.network).as_str().unwrap_or_default()),
+        }
+    }
+
+    /// Prevouts that other wallets funded in shared transactions not yet sent to
+    /// bookkeeper. The sync only finds our own, but splitting the fee needs all of them.
+    fn missing_prevouts<'a>(
+        &self,
+        wallet: &Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+    ) -> Vec<OutPoint> {
+        let graph = wallet.tx_graph();
+        let mut missing = Vec::new();
+        for canonical_tx in wallet.transactions() {
+            if self.transactions.contains_key(&canonical_tx.node.txid) {
+                continue;
+            }
+            let prevouts = canonical_tx
+                .node
+                .tx
+                .input
+                .iter()
+                .map(|input| input.previous_output);
+            let (found, not_found): (Vec<_>, Vec<_>) =
+                prevouts.partition(|outpoint| graph.get_txout(*outpoint).is_some());
+            let ours = found.iter().any(|outpoint| {
+                graph
+                    .get_txout(*outpoint)
+                    .is_some_and(|po| wallet.is_mine(&po.script_pubkey))
+            });
+            if ours {
+                missing.extend(not_found);
+            }
+        }
+        missing
+    }
+
+    /// Look up the prevouts from [`Self::missing_prevouts`] with `backend` and add
+    /// them to the wallet's tx graph. Failures are only logged: the fee of those
+    /// transactions stays unknown. lightningd's backend can't look up spent outputs,
+    /// so it is skipped.
+    async fn fetch_prevouts<'a>(
+        &self,
+        wallet: &mut Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>,
+        backend: &Backend,
+    ) {
+        let missing = self.missing_prevouts(wallet);
+        if missing.is_empty() {
+            return;
+        }
+        log::info!("fetching {} prevouts of shared transactions", missing.len());
+        let outputs = match backend {
+            Backend::Esplora { url } => self.esplora_outputs(url, &missing).await,
+            Backend::Electrum { url } => {
+                tokio::task::block_in_place(|| electrum_outputs(url, &missing))
+            }
+            Backend::Bitcoind(config) => {
+                tokio::task::block_in_place(|| bitcoind_outputs(config, &missing))
+            }
+            Backend::Cln { .. } => {
+                log::info!("lightningd's bitcoin backend can't look up prevouts");
+                return;
+            }
+        };
+        let outputs = match outputs {
+            Ok(outputs) => outputs,
+            Err(e) => {
+                log::warn!("Error fetching prevouts: {:#}", e);
+                return;
+            }
+        };
+        let mut update = LocalUpdate::new(LocalChain::from(wallet.checkpoints().clone()));
+        for (outpoint, txout) in outputs {
+            let _ = update.graph.insert_txout(outpoint, txout);
+        }
+        if let Err(e) = wallet.apply_update(update) {
+            log::warn!("Error adding prevouts to the wallet: {:?}", e);
+        }
+    }
+
+    async fn esplora_outputs(
+        &self,
+        url: &Option<String>,
+        outpoints: &[OutPoint],
+    ) -> Result<Vec<(OutPoint, TxOut)>, Error> {
+        let client = esplora_client::Builder::new(&self.esplora_url(url)?).build_async()?;
+        let txids = outpoints.iter().map(|o| o.txid).collect::<HashSet<_>>();
+        let mut outputs = Vec::new();
+        for txid in txids {
+            if let Some(tx) = client.get_tx(&txid).await? {
+                outputs.extend(outputs_of(&tx, outpoints));
+            }
+        }
+        Ok(outputs)
+    }
+
+    fn store_error(&self, e: impl fmt::Debug) -> SmaugError {
+        SmaugError::Store {
+            wallet: self.get_name().unwrap_or_default(),
+            message: format!("{:?}", e),
+        }
+    }
+
+    /// Open the wallet's BDK store without syncing it.
+    pub fn open_wallet<'a>(
         &self,
-        // dw: &DescriptorWallet,
     ) -> Result<Wallet<Store<'a, LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>>>, Error>
     {
         log::info!("creating path");
-        let db_filename = self.get_name()?;
-        let db_path = home_dir()
-            .unwrap()
-            .join(DATADIR)
-            .join(format!("{}.db", db_filename,));
+        let db_path = self.db_path()?;
         log::info!("searching for path: {:?}", db_path);
-        let db = Store::<bdk::wallet::ChangeSet>::new_from_path(DATADIR.as_bytes(), db_path)?;
+        let db = Store::<bdk::wallet::ChangeSet>::new_from_path(DATADIR.as_bytes(), db_path)
+            .map_err(|e| self.store_error(e))?;
         log::info!("db created!");
         // let external_descriptor = "wpkh(tprv8ZgxMBicQKsPdy6LMhUtFHAgpocR8GC6QmwMSFpZs7h6Eziw3SpThFfczTDh5rW2krkqffa11UpX3XkeTTB2FvzZKWXqPY54Y6Rq4AQ5R8L/84'/0'/0'/0/*)";
         // mutinynet_descriptor = "wpkh(tprv8ZgxMBicQKsPdSAgthqLZ5ZWQkm5As4V3qNA5G8KKxGuqdaVVtBhytrUqRGPm4RxTktSdvch8JyUdfWR8g3ddrC49WfZnj4iGZN8y5L8NPZ/*)"
@@ -250,67 +1751,18 @@ impl DescriptorWallet {
         //     &dw.descriptor,
         //     &dw.change_descriptor,
         // );
-        let mut wallet = Wallet::new(
+        let wallet = Wallet::new(
             &external_descriptor,
             internal_descriptor.as_ref(),
             db,
-            Network::Testnet,
-        )?;
+            self.network()?,
+        )
+        .map_err(|e| self.store_error(e))?;
         log::info!("wallet created!");
 
         // let address = wallet.get_address(AddressIndex::New);
         // log::info!("Generated Address: {}", address);
-
-        let balance = wallet.get_balance();
-        log::info!("Wallet balance before syncing: {} sats", balance.total());
-
-        log::info!("Syncing...");
-        log::info!("using network: {}", json!(self.network).as_str().unwrap());
-        log::info!(
-            "using esplora url: {}",
-            get_network_url(json!(self.network).as_str().unwrap()).as_str()
-        );
-        let client =
-            // esplora_client::Builder::new("https://blockstream.info/testnet/api").build_async()?;
-            esplora_client::Builder::new(
-                get_network_url(
-                        json!(self.network).as_str().unwrap()
-                ).as_str()
-            ).build_async()?;
-
-        let local_chain = wallet.checkpoints();
-        let keychain_spks = wallet
-            .spks_of_all_keychains()
-            .into_iter()
-            .map(|(k, k_spks)| {
-                let mut once = Some(());
-                let mut stdout = std::io::stdout();
-                let k_spks = k_spks
-                    .inspect(move |(spk_i, _)| match once.take() {
-                        Some(_) => log::info!("\nScanning keychain [{:?}]", k),
-                        None => log::info!(" {:<3}", spk_i),
-                    })
-                    .inspect(move |_| stdout.flush().expect("must flush"));
-                (k, k_spks)
-            })
-            .collect();
-        log::info!("CAG finished scanning");
-        let update = client
-            .scan(
-                local_chain,
-                keychain_spks,
-                [],
-                [],
-                STOP_GAP,
-                PARALLEL_REQUESTS,
-            )
-            .await?;
-        wallet.apply_update(update)?;
-        wallet.commit()?;
-
-        let balance = wallet.get_balance();
-        log::info!("Wallet balance after syncing: {} sats", balance.total());
-        return Ok(wallet);
+        Ok(wallet)
     }
 
     // assume we own all inputs, ie sent from our wallet. all inputs and outputs should generate coin movement bookkeeper events
@@ -330,32 +1782,17 @@ impl DescriptorWallet {
                                 continue;
                             }
                             ConfirmationTime::Confirmed { height, time } => {
-                                let acct = format!("smaug:{}", self.get_name()?);
-                                let amount = po.value;
-                                let outpoint = format!("{}", input.previous_output.to_string());
-                                log::info!("outpoint = {}", format!("{}", outpoint));
-                                let onchain_spend = json!({
-                                    "account": acct,
-                                    "outpoint": outpoint,
-                                    "spending_txid": tx.txid.to_string(),
-                                    "amount_msat": amount,
-                                    "coin_type": "bcrt",
-                                    "timestamp": format!("{}", time),
-                                    "blockheight": format!("{}", height),
-                                });
-                                log::info!("INSIDE SEND SPEND NOTIFICATION ON SMAUG SIDE");
-                                let cloned_plugin = plugin.clone();
-                                tokio::spawn(async move {
-                                    if let Err(e) = cloned_plugin
-                                        .send_custom_notification(
-                                            UTXO_SPENT_TAG.to_string(),
-                                            onchain_spend,
-                                        )
-                                        .await
-                                    {
-                                        log::error!("Error sending custom notification: {:?}", e);
-                                    }
-                                });
+                                log::info!("outpoint = {}", input.previous_output);
+                                let onchain_spend = UtxoSpent {
+                                    account: format!("smaug:{}", self.get_name()?),
+                                    outpoint: input.previous_output,
+                                    spending_txid: tx.txid,
+                                    amount_msat: Msat::from_sat(po.value),
+                                    coin_type: coin_type(self.network()?),
+                                    timestamp: time,
+                                    blockheight: height,
+                                };
+                                notify(plugin, UTXO_SPENT_TAG, onchain_spend).await?;
                             }
                         }
                     } else {
@@ -379,38 +1816,40 @@ impl DescriptorWallet {
                                 transfer_from = format!("smaug:{}", self.get_name()?);
                                 acct = "external".to_owned();
                             }
-                            let amount = output.value;
-                            let outpoint = format!("{}:{}", tx.txid.to_string(), vout.to_string());
-                            log::info!(
-                                "outpoint = {}",
-                                format!("{}:{}", tx.txid.to_string(), vout.to_string())
-                            );
-                            let onchain_deposit = json!({
-                                    "account": acct,
-                                    "transfer_from": transfer_from,
-                                    "outpoint": outpoint,
-                                    "spending_txid": tx.txid.to_string(),
-                                    "amount_msat": amount,
-                                    "coin_type": "bcrt",
-                                    "timestamp": format!("{}", time),
-                                    "blockheight": format!("{}", height),
-                            });
-                            log::info!("INSIDE SEND DEPOSIT NOTIFICATION ON SMAUG SIDE");
-                            let cloned_plugin = plugin.clone();
-                            tokio::spawn(async move {
-                                if let Err(e) = cloned_plugin
-                                    .send_custom_notification(
-                                        UTXO_DEPOSIT_TAG.to_string(),
-                                        onchain_deposit,
-                                    )
-                                    .await
-                                {
-                                    log::error!("Error sending custom notification: {:?}", e);
-                                }
-                            });
+                            let outpoint = OutPoint::new(tx.txid, vout as u32);
+                            log::info!("outpoint = {}", outpoint);
+                            let onchain_deposit = UtxoDeposit {
+                                account: acct,
+                                transfer_from,
+                                outpoint,
+                                spending_txid: tx.txid,
+                                amount_msat: Msat::from_sat(output.value),
+                                coin_type: coin_type(self.network()?),
+                                timestamp: time,
+                                blockheight: height,
+                            };
+                            notify(plugin, UTXO_DEPOSIT_TAG, onchain_deposit).await?;
                         }
                     }
                 }
+
+                // we paid the whole fee
+                if let ConfirmationTime::Confirmed { height, time } = tx.confirmation_time {
+                    match tx_fee(wallet, tx) {
+                        Some(fee) => {
+                            let onchain_fee = OnchainFee {
+                                account: format!("smaug:{}", self.get_name()?),
+                                txid: tx.txid,
+                                amount_msat: Msat::from_sat(fee),
+                                coin_type: coin_type(self.network()?),
+                                timestamp: time,
+                                blockheight: height,
+                            };
+                            notify(plugin, ONCHAIN_FEE_TAG, onchain_fee).await?;
+                        }
+                        None => log::info!("fee of {} is unknown", tx.txid),
+                    }
+                }
             }
             None => {
                 log::info!("TransactionDetails is missing a Transaction");
@@ -437,49 +1876,19 @@ impl DescriptorWallet {
                                 continue;
                             }
                             ConfirmationTime::Confirmed { height, time } => {
-                                let acct: String;
-                                let transfer_from: String;
-                                if wallet.is_mine(&output.script_pubkey) {
-                                    acct = format!("smaug:{}", self.get_name()?);
-                                    transfer_from = "external".to_owned();
-                                } else {
-                                    // transfer_from = format!(
-                                    //     "smaug:{}",
-                                    //     self.get_name?
-                                    // );
-                                    // acct = "external".to_owned();
-                                    continue;
-                                }
-                                let amount = output.value;
-                                let outpoint =
-                                    format!("{}:{}", tx.txid.to_string(), vout.to_string());
-                                log::info!(
-                                    "outpoint = {}",
-                                    format!("{}:{}", tx.txid.to_string(), vout.to_string())
-                                );
-                                let onchain_deposit = json!({
-                                        "account": acct,
-                                        "transfer_from": transfer_from,
-                                        "outpoint": outpoint,
-                                        "spending_txid": tx.txid.to_string(),
-                                        "amount_msat": amount,
-                                        "coin_type": "bcrt",
-                                        "timestamp": format!("{}", time),
-                                        "blockheight": format!("{}", height),
-                                });
-                                log::info!("INSIDE SEND DEPOSIT NOTIFICATION ON SMAUG SIDE");
-                                let cloned_plugin = plugin.clone();
-                                tokio::spawn(async move {
-                                    if let Err(e) = cloned_plugin
-                                        .send_custom_notification(
-                                            UTXO_DEPOSIT_TAG.to_string(),
-                                            onchain_deposit,
-                                        )
-                                        .await
-                                    {
-                                        log::error!("Error sending custom notification: {:?}", e);
-                                    }
-                                });
+                                let outpoint = OutPoint::new(tx.txid, vout as u32);
+                                log::info!("outpoint = {}", outpoint);
+                                let onchain_deposit = UtxoDeposit {
+                                    account: format!("smaug:{}", self.get_name()?),
+                                    transfer_from: "external".to_owned(),
+                                    outpoint,
+                                    spending_txid: tx.txid,
+                                    amount_msat: Msat::from_sat(output.value),
+                                    coin_type: coin_type(self.network()?),
+                                    timestamp: time,
+                                    blockheight: height,
+                                };
+                                notify(plugin, UTXO_DEPOSIT_TAG, onchain_deposit).await?;
                             }
                         }
                     }
@@ -513,35 +1922,17 @@ impl DescriptorWallet {
                             }
                             ConfirmationTime::Confirmed { height, time } => {
                                 if wallet.is_mine(&po.script_pubkey) {
-                                    let acct = format!("smaug:{}", self.get_name()?);
-                                    let amount = po.value;
-                                    let outpoint = format!("{}", input.previous_output.to_string());
-                                    log::info!("outpoint = {}", format!("{}", outpoint));
-                                    let onchain_spend = json!({
-                                        "account": acct,
-                                        "outpoint": outpoint,
-                                        "spending_txid": tx.txid.to_string(),
-                                        "amount_msat": amount,
-                                        "coin_type": "bcrt",
-                                        "timestamp": format!("{}", time),
-                                        "blockheight": format!("{}", height),
-                                    });
-                                    log::info!("INSIDE SEND SPEND NOTIFICATION ON SMAUG SIDE");
-                                    let cloned_plugin = plugin.clone();
-                                    tokio::spawn(async move {
-                                        if let Err(e) = cloned_plugin
-                                            .send_custom_notification(
-                                                UTXO_SPENT_TAG.to_string(),
-                                                onchain_spend,
-                                            )
-                                            .await
-                                        {
-                                            log::error!(
-                                                "Error sending custom notification: {:?}",
-                                                e
-                                            );
-                                        }
-                                    });
+                                    log::info!("outpoint = {}", input.previous_output);
+                                    let onchain_spend = UtxoSpent {
+                                        account: format!("smaug:{}", self.get_name()?),
+                                        outpoint: input.previous_output,
+                                        spending_txid: tx.txid,
+                                        amount_msat: Msat::from_sat(po.value),
+                                        coin_type: coin_type(self.network()?),
+                                        timestamp: time,
+                                        blockheight: height,
+                                    };
+                                    notify(plugin, UTXO_SPENT_TAG, onchain_spend).await?;
                                 }
                             }
                         }
@@ -569,36 +1960,50 @@ impl DescriptorWallet {
                                 acct = ext_acct;
                                 transfer_from = our_acct;
                             }
-                            let amount = output.value;
-                            let outpoint = format!("{}:{}", tx.txid.to_string(), vout.to_string());
-                            log::info!(
-                                "outpoint = {}",
-                                format!("{}:{}", tx.txid.to_string(), vout.to_string())
-                            );
-                            let onchain_deposit = json!({
-                                    "account": acct,
-                                    "transfer_from": transfer_from,
-                                    "outpoint": outpoint,
-                                    "spending_txid": tx.txid.to_string(),
-                                    "amount_msat": amount,
-                                    "coin_type": "bcrt",
-                                    "timestamp": format!("{}", time),
-                                    "blockheight": format!("{}", height),
-                            });
-                            log::info!("INSIDE SEND DEPOSIT NOTIFICATION ON SMAUG SIDE");
-                            let cloned_plugin = plugin.clone();
-                            tokio::spawn(async move {
-                                if let Err(e) = cloned_plugin
-                                    .send_custom_notification(
-                                        UTXO_DEPOSIT_TAG.to_string(),
-                                        onchain_deposit,
-                                    )
-                                    .await
-                                {
-                                    log::error!("Error sending custom notification: {:?}", e);
-                                }
-                            });
+                            let outpoint = OutPoint::new(tx.txid, vout as u32);
+                            log::info!("outpoint = {}", outpoint);
+                            let onchain_deposit = UtxoDeposit {
+                                account: acct,
+                                transfer_from,
+                                outpoint,
+                                spending_txid: tx.txid,
+                                amount_msat: Msat::from_sat(output.value),
+                                coin_type: coin_type(self.network()?),
+                                timestamp: time,
+                                blockheight: height,
+                            };
+                            notify(plugin, UTXO_DEPOSIT_TAG, onchain_deposit).await?;
+                        }
+                    }
+                }
+
+                // we paid our share of the fee, in proportion to the value of our inputs
+                if let ConfirmationTime::Confirmed { height, time } = tx.confirmation_time {
+                    let our_inputs = t
+                        .input
+                        .iter()
+                        .filter_map(|input| wallet.tx_graph().get_txout(input.previous_output))
+                        .filter(|po| wallet.is_mine(&po.script_pubkey))
+                        .map(|po| po.value)
+                        .sum::<u64>();
+                    match tx_fee(wallet, tx) {
+                        Some(fee) => {
+                            let total_inputs =
+                                t.output.iter().map(|output| output.value).sum::<u64>() + fee;
+                            let fee_share_msat = (fee as u128 * 1000 * our_inputs as u128
+                                / total_inputs.max(1) as u128)
+                                as u64;
+                            let onchain_fee = OnchainFee {
+                                account: format!("smaug:{}", self.get_name()?),
+                                txid: tx.txid,
+                                amount_msat: Msat::from_msat(fee_share_msat),
+                                coin_type: coin_type(self.network()?),
+                                timestamp: time,
+                                blockheight: height,
+                            };
+                            notify(plugin, ONCHAIN_FEE_TAG, onchain_fee).await?;
                         }
+                        None => log::info!("fee of {} is unknown", tx.txid),
                     }
                 }
             }
@@ -616,50 +2021,22 @@ impl DescriptorWallet {
         tx: TransactionDetails,
     ) -> Result<(), Error> {
         log::info!("sending notifs for txid/tx: {:?} {:?}", tx.txid, tx);
-        // we own all inputs
-        if tx.clone().transaction.unwrap().input.iter().all(|x| {
-            match wallet.tx_graph().get_txout(x.previous_output) {
-                Some(o) => {
-                    log::info!(
-                        "output is mine?: {:?} {:?}",
-                        o,
-                        wallet.is_mine(&o.script_pubkey)
-                    );
-                    wallet.is_mine(&o.script_pubkey)
-                }
-                None => {
-                    log::info!("output not found in tx graph: {:?}", x.previous_output);
-                    false
-                }
+        let t = tx.transaction.as_ref().ok_or_else(|| {
+            SmaugError::Internal(format!("transaction {} has no raw transaction", tx.txid))
+        })?;
+        match tx_kind(wallet, t) {
+            TxKind::Spend => {
+                log::info!("sending spend notif");
+                self.spend_tx_notify(plugin, wallet, &tx).await?;
             }
-        }) {
-            log::info!("sending spend notif");
-            self.spend_tx_notify(plugin, wallet, &tx).await?;
-        } else
-        // we own no inputs
-        if !tx.clone().transaction.unwrap().input.iter().any(|x| {
-            match wallet.tx_graph().get_txout(x.previous_output) {
-                Some(o) => {
-                    log::info!(
-                        "output is mine?: {:?} {:?}",
-                        o,
-                        wallet.is_mine(&o.script_pubkey)
-                    );
-                    wallet.is_mine(&o.script_pubkey)
-                }
-                None => {
-                    log::info!("output not found in tx graph: {:?}", x.previous_output);
-                    false
-                }
+            TxKind::Receive => {
+                log::info!("sending deposit notif");
+                self.receive_tx_notify(plugin, wallet, &tx).await?;
+            }
+            TxKind::Shared => {
+                log::info!("sending shared notif");
+                self.shared_tx_notify(plugin, wallet, &tx).await?;
             }
-        }) {
-            log::info!("sending deposit notif");
-            self.receive_tx_notify(plugin, wallet, &tx).await?;
-        }
-        // we own some inputs but not others
-        else {
-            log::info!("sending shared notif");
-            self.shared_tx_notify(plugin, wallet, &tx).await?;
         }
 
         // if tx.sent > 0 {
@@ -673,76 +2050,269 @@ impl DescriptorWallet {
     }
 }
 
-impl TryFrom<serde_json::Value> for DescriptorWallet {
-    type Error = WatchError;
-
-    fn try_from(value: serde_json::Value) -> Result<Self, Self::Error> {
-        log::info!("entering try_from");
-        match value {
-            serde_json::Value::Array(a) => {
-                log::info!("try_from: array detected = {:?}", a);
-                let param_count = a.len();
-
-                match param_count {
-                    // 1 => DescriptorWallet::try_from(a.pop().unwrap()),
-                    1..=4 => {
-                        let descriptor = a.get(0).unwrap().as_str().ok_or_else(|| WatchError::InvalidDescriptor("descriptor must be a string".to_string()))?;
-                        // let change_descriptor = Some(a.get(1).unwrap().as_str().ok_or_else(|| WatchError::InvalidChangeDescriptor("change_descriptor must be a string".to_string()))?);
-                        log::info!("try_from array: change_descriptor = {:?}", a.get(1));
-                        let change_descriptor = if let Some(cd) = a.get(1) {
-                            Some(cd.as_str().ok_or_else(|| WatchError::InvalidChangeDescriptor(format!("change_descriptor must be a string. Received: {cd}")))?)
-                        } else {
-                            None
-                        };
-                        let birthday = if let Some(b) = a.get(2) {
-                            Some(b.as_u64().ok_or_else(|| WatchError::InvalidBirthday(format!("birthday must be a number. Received: {b}")))?)
-                        } else {
-                            None
-                        };
-                        let gap = if let Some(g) = a.get(3) {
-                            Some(g.as_u64().ok_or_else(|| WatchError::InvalidGap(format!("gap must be a number. Received: {g}")))?)
-                        } else {
-                            None
-                        };
-
-                        DescriptorWallet::new(descriptor, change_descriptor, birthday, gap, None)
-                    }
-                    _ => Err(WatchError::InvalidFormat(format!("Unexpected request format. The request needs 1-4 parameters. Received: {param_count}"))),
-                }
-            },
-            serde_json::Value::Object(m) => {
-                log::info!("try_from: object detected");
-                let allowed_keys = ["descriptor", "change_descriptor", "birthday", "gap"];
-                let param_count = m.len();
-
-                 if m.is_empty() || param_count > allowed_keys.len() {
-                    Err(WatchError::InvalidFormat(format!("Unexpected request format. The request needs 1-4 parameters. Received: {param_count}")))
-                 } else if !m.contains_key(allowed_keys[0]){
-                    Err(WatchError::InvalidDescriptor(format!("{} is mandatory", allowed_keys[0])))
-                 } else if !m.iter().all(|(k, _)| allowed_keys.contains(&k.as_str())) {
-                    Err(WatchError::InvalidFormat(format!("Invalid named parameter found in request. Allowed named params: ['descriptor', 'change_descriptor', 'birthday', 'gap']")))
-                 } else {
-                    DescriptorWallet::new(
-                        m.get("descriptor").unwrap().as_str().unwrap(),
-                        match m.get("change_descriptor") {
-                            Some(v) => Some(v.as_str().unwrap()),
-                            None => None,
-                        },
-                        match m.get("birthday") {
-                            Some(v) => Some(v.as_u64().unwrap()),
-                            None => None,
-                        },
-                        match m.get("gap") {
-                            Some(v) => Some(v.as_u64().unwrap()),
-                            None => None,
-                        },
-                        None,
-                    )
-                }
-            },
-            _ => Err(WatchError::InvalidFormat(
-                format!("Unexpected request format. Expected: <descriptor>, [change_descriptor, birthday, gap], either as ordered or keyword args. Received: '{value}'"),
-            )),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bdk::bitcoin::hashes::Hash;
+
+    fn block(height: u32, byte: u8) -> BlockId {
+        BlockId {
+            height,
+            hash: BlockHash::hash(&[byte]),
         }
     }
+
+    #[test]
+    fn reorged_decision() {
+        let confirmed = ConfirmationTime::Confirmed {
+            height: 10,
+            time: 100,
+        };
+        let recorded = Some(&confirmed);
+        let checkpoints = [block(10, 1), block(12, 1)]
+            .iter()
+            .map(|b| (b.height, b.hash))
+            .collect::<BTreeMap<_, _>>();
+
+        // still in the same block
+        assert!(!is_reorged(
+            &checkpoints,
+            &block(10, 1),
+            recorded,
+            Some(&confirmed),
+            true
+        ));
+        // the block was replaced
+        assert!(is_reorged(
+            &checkpoints,
+            &block(10, 2),
+            recorded,
+            None,
+            true
+        ));
+        // the block was removed, and the transaction lost a conflict
+        assert!(is_reorged(
+            &checkpoints,
+            &block(11, 1),
+            recorded,
+            None,
+            true
+        ));
+        // ... but a store without the transaction is no evidence
+        assert!(!is_reorged(
+            &checkpoints,
+            &block(11, 1),
+            recorded,
+            None,
+            false
+        ));
+        // above the tip, the block may just not be synced yet
+        assert!(!is_reorged(
+            &checkpoints,
+            &block(13, 1),
+            recorded,
+            None,
+            true
+        ));
+        // back in the mempool, or confirmed elsewhere
+        let unconfirmed = ConfirmationTime::Unconfirmed { last_seen: 100 };
+        assert!(is_reorged(
+            &checkpoints,
+            &block(10, 1),
+            recorded,
+            Some(&unconfirmed),
+            true
+        ));
+        let moved = ConfirmationTime::Confirmed {
+            height: 12,
+            time: 120,
+        };
+        assert!(is_reorged(
+            &checkpoints,
+            &block(10, 1),
+            recorded,
+            Some(&moved),
+            true
+        ));
+    }
+
+    #[test]
+    fn coin_type_follows_network() {
+        assert_eq!(coin_type(Network::Bitcoin), "bc");
+        assert_eq!(coin_type(Network::Testnet), "tb");
+        assert_eq!(coin_type(Network::Signet), "tbs");
+        assert_eq!(coin_type(Network::Regtest), "bcrt");
+    }
+
+    const TPUB: &str = "tpubD6NzVbkrYhZ4WuCUnMVvxVDcynH1LCFPd8xwMnAckE5Jg7qG8H1JAPUM1XM6PUrDJkkavEYjAysfrcYkTP4uN5maxw33fYynKXFUpKHpBbM";
+    const XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+    const PUBKEY: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+    #[test]
+    fn key_network_of_extended_keys() {
+        assert_eq!(
+            key_network(&format!("wpkh({TPUB}/0/*)")),
+            Some(Network::Testnet)
+        );
+        assert_eq!(
+            key_network(&format!("wpkh({XPUB}/0/*)")),
+            Some(Network::Bitcoin)
+        );
+        // the first extended key decides
+        assert_eq!(
+            key_network(&format!("wsh(multi(1,{PUBKEY},{XPUB}/0/*,{TPUB}/0/*))")),
+            Some(Network::Bitcoin)
+        );
+        // single keys don't tell, and neither do unparseable descriptors
+        assert_eq!(key_network(&format!("wpkh({PUBKEY})")), None);
+        assert_eq!(key_network("wpkh(foo)"), None);
+    }
+
+    const TPRV: &str = "tprv8ZgxMBicQKsPdSAgthqLZ5ZWQkm5As4V3qNA5G8KKxGuqdaVVtBhytrUqRGPm4RxTktSdvch8JyUdfWR8g3ddrC49WfZnj4iGZN8y5L8NPZ";
+    const XPRV: &str = "xprv9s21ZrQH143K3QTDL4LXw2F7HEK3wJUD2nW2nRk4stbPy6cq3jPPqjiChkVvvNKmPGJxWUtg6LnF5kejMRNNU3TGtRBeJgk33yuGBxrMPHi";
+
+    #[test]
+    fn private_keys_are_detected() {
+        assert!(has_private_keys(&format!("wpkh({TPRV}/0/*)")));
+        assert!(has_private_keys(&format!(
+            "wsh(multi(1,{XPUB}/0/*,{XPRV}/0/*))"
+        )));
+        assert!(!has_private_keys(&format!("wpkh({TPUB}/0/*)")));
+        assert!(!has_private_keys("wpkh(foo)"));
+    }
+
+    #[test]
+    fn redact_keeps_only_public_keys() {
+        let redacted = redact(&format!("wpkh({TPRV}/0/*)"));
+        assert!(redacted.starts_with(&format!("wpkh({TPUB}/0/*)")));
+        assert!(!redacted.contains("prv"));
+
+        let redacted = redact(&format!("wsh(multi(1,{XPUB}/0/*,{XPRV}/0/*))"));
+        assert!(redacted.starts_with(&format!("wsh(multi(1,{XPUB}/0/*,{XPUB}/0/*))")));
+        assert!(!redacted.contains(XPRV));
+
+        assert_eq!(
+            redact(&format!("wpkh({TPRV}/0/*")),
+            "<unparseable descriptor>"
+        );
+    }
+
+    fn coin(keychain: KeychainKind, value: u64, height: Option<u32>, coinbase: bool) -> Coin {
+        Coin {
+            keychain,
+            value,
+            height,
+            coinbase,
+        }
+    }
+
+    #[test]
+    fn add_coin_splits_like_bdk() {
+        let mut balance = Balance::default();
+        balance.add_coin(&coin(KeychainKind::External, 1, Some(10), false), 200);
+        balance.add_coin(&coin(KeychainKind::Internal, 2, None, false), 200);
+        balance.add_coin(&coin(KeychainKind::External, 4, None, false), 200);
+        // a coinbase output matures once it has COINBASE_MATURITY confirmations
+        balance.add_coin(&coin(KeychainKind::External, 8, Some(101), true), 199);
+        balance.add_coin(&coin(KeychainKind::External, 16, Some(100), true), 199);
+        assert_eq!(
+            balance,
+            Balance {
+                confirmed_sat: 17,
+                trusted_pending_sat: 2,
+                untrusted_pending_sat: 4,
+                immature_sat: 8,
+                total_sat: 31,
+            }
+        );
+
+        let mut total = balance;
+        total.add(&balance);
+        assert_eq!(total.confirmed_sat, 34);
+        assert_eq!(total.total_sat, 62);
+    }
+
+    fn tx_item(height: Option<u32>, timestamp: Option<u64>, direction: Direction) -> TxListItem {
+        TxListItem {
+            wallet: "wallet".to_owned(),
+            txid: Txid::all_zeros(),
+            height,
+            timestamp,
+            sent_sat: 0,
+            received_sat: 0,
+            fee_sat: None,
+            net_sat: 0,
+            kind: TxKind::Receive,
+            direction,
+            status: TxStatus::Notified,
+            replaced_by: None,
+        }
+    }
+
+    #[test]
+    fn tx_filter() {
+        let confirmed = tx_item(Some(100), Some(1_000), Direction::Incoming);
+        let unconfirmed = tx_item(None, Some(2_000), Direction::Outgoing);
+        let untimed = tx_item(Some(100), None, Direction::Incoming);
+
+        let all = TxFilter::default();
+        assert!(all.matches(&confirmed) && all.matches(&unconfirmed) && all.matches(&untimed));
+
+        let heights = TxFilter {
+            min_height: Some(100),
+            max_height: Some(100),
+            ..Default::default()
+        };
+        assert!(heights.matches(&confirmed));
+        assert!(!heights.matches(&unconfirmed));
+        let above = TxFilter {
+            min_height: Some(101),
+            ..Default::default()
+        };
+        assert!(!above.matches(&confirmed));
+
+        let times = TxFilter {
+            since: Some(1_000),
+            until: Some(1_500),
+            ..Default::default()
+        };
+        assert!(times.matches(&confirmed));
+        assert!(!times.matches(&unconfirmed));
+        assert!(!times.matches(&untimed));
+
+        let outgoing = TxFilter {
+            direction: Some(Direction::Outgoing),
+            ..Default::default()
+        };
+        assert!(!outgoing.matches(&confirmed));
+        assert!(outgoing.matches(&unconfirmed));
+    }
+
+    #[test]
+    fn direction_from_str() {
+        assert_eq!("in".parse::<Direction>().unwrap(), Direction::Incoming);
+        assert_eq!(
+            "outgoing".parse::<Direction>().unwrap(),
+            Direction::Outgoing
+        );
+        assert!("sideways".parse::<Direction>().is_err());
+    }
+
+    #[test]
+    fn utxo_filter() {
+        assert!(UtxoFilter::default().matches(0, 0));
+        let filter = UtxoFilter {
+            min_value: Some(1_000),
+            max_value: Some(5_000),
+            min_depth: Some(1),
+            max_depth: Some(6),
+        };
+        assert!(filter.matches(1_000, 1));
+        assert!(filter.matches(5_000, 6));
+        assert!(!filter.matches(999, 3));
+        assert!(!filter.matches(5_001, 3));
+        // unconfirmed coins have depth 0
+        assert!(!filter.matches(2_000, 0));
+        assert!(!filter.matches(2_000, 7));
+    }
 }
@@ -0,0 +1,69 @@
+//! Syncs against a local regtest bitcoind. Run with
+//!
+//! ```text
+//! SMAUG_BITCOIND_URL=http://127.0.0.1:18443 SMAUG_BITCOIND_COOKIE=$HOME/.bitcoin/regtest/.cookie \
+//!     cargo test --test regtest -- --ignored
+//! ```
+//!
+//! `SMAUG_BITCOIND_USER` and `SMAUG_BITCOIND_PASSWORD` can be used instead of the
+//! cookie. The test mines blocks, so the node must be on regtest.
+
+use bdk::{
+    bitcoin::Network,
+    chain::{keychain::LocalChangeSet, ConfirmationTimeAnchor},
+    wallet::AddressIndex,
+    KeychainKind, Wallet,
+};
+use bdk_file_store::Store;
+use bitcoincore_rpc::{Auth, Client, RpcApi};
+use smaug::backend::{sync_bitcoind, BitcoindConfig};
+use std::env;
+
+const DESCRIPTOR: &str = "wpkh(tpubD6NzVbkrYhZ4WuCUnMVvxVDcynH1LCFPd8xwMnAckE5Jg7qG8H1JAPUM1XM6PUrDJkkavEYjAysfrcYkTP4uN5maxw33fYynKXFUpKHpBbM/0/*)";
+const CHANGE_DESCRIPTOR: &str = "wpkh(tpubD6NzVbkrYhZ4WuCUnMVvxVDcynH1LCFPd8xwMnAckE5Jg7qG8H1JAPUM1XM6PUrDJkkavEYjAysfrcYkTP4uN5maxw33fYynKXFUpKHpBbM/1/*)";
+
+#[test]
+#[ignore = "needs a regtest bitcoind, see the module docs"]
+fn sync_bitcoind_regtest() {
+    let url = env::var("SMAUG_BITCOIND_URL").expect("SMAUG_BITCOIND_URL");
+    let cookie = env::var("SMAUG_BITCOIND_COOKIE").ok();
+    let user = env::var("SMAUG_BITCOIND_USER").ok();
+    let password = env::var("SMAUG_BITCOIND_PASSWORD").ok();
+    let auth = match (&cookie, &user, &password) {
+        (Some(cookie), _, _) => Auth::CookieFile(cookie.into()),
+        (None, Some(user), Some(password)) => Auth::UserPass(user.clone(), password.clone()),
+        _ => Auth::None,
+    };
+    let client = Client::new(&url, auth).expect("bitcoind client");
+    let mut config = BitcoindConfig::new(url, cookie, user, password).expect("config");
+    config.mempool = true;
+
+    let path = env::temp_dir().join(format!("smaug-regtest-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let db: Store<LocalChangeSet<KeychainKind, ConfirmationTimeAnchor>> =
+        Store::new_from_path("smaug-test".as_bytes(), &path).expect("store");
+    let mut wallet =
+        Wallet::new(DESCRIPTOR, Some(CHANGE_DESCRIPTOR), db, Network::Regtest).expect("wallet");
+
+    let address = wallet.get_address(AddressIndex::New);
+    client
+        .generate_to_address(101, &address.address)
+        .expect("mine to the wallet");
+    let tip = client.get_block_count().expect("block count") as u32;
+
+    sync_bitcoind(&mut wallet, &config, None, 20).expect("first sync");
+    assert_eq!(wallet.checkpoints().keys().next_back(), Some(&tip));
+    // all but the last 100 coinbase outputs are mature
+    let balance = wallet.get_balance();
+    assert!(balance.confirmed > 0);
+    assert!(balance.immature > 0);
+
+    // an incremental sync resumes from the tip and finds the new block
+    client
+        .generate_to_address(1, &address.address)
+        .expect("mine another block");
+    sync_bitcoind(&mut wallet, &config, None, 20).expect("second sync");
+    assert_eq!(wallet.checkpoints().keys().next_back(), Some(&(tip + 1)));
+
+    let _ = std::fs::remove_file(&path);
+}